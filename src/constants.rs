@@ -3,4 +3,8 @@ pub const TYPENODENORMAL: u8 = 1;
 pub const TYPENODEFINAL: u8 = 2;
 pub const TYPENODEVALUE: u8 = 3;
 pub const TYPENODEROOT: u8 = 4;
+/// Final node keeping the hi and ht of its leaf next to its bytes, see
+/// `node::Leaf`. The final nodes written by older versions are of type
+/// `TYPENODEFINAL` and hold only the bytes of the leaf.
+pub const TYPENODEFINALLEAF: u8 = 5;
 pub const EMPTYNODEVALUE: [u8; 32] = [0; 32];
@@ -1,49 +1,532 @@
 extern crate rusty_leveldb;
 
-use self::rusty_leveldb::DB;
+use self::rusty_leveldb::{LdbIterator, WriteBatch, DB};
+use std::collections::HashMap;
 
 use super::constants;
+use super::utils;
+use super::Error;
+use super::Result;
+
+/// A single write staged in a `Batch`.
+pub enum BatchOp {
+    Insert([u8; 32], Vec<u8>),
+    Delete([u8; 32]),
+}
+
+/// Group of writes that a `Storage` applies in one go.
+#[derive(Default)]
+pub struct Batch {
+    pub ops: Vec<BatchOp>,
+}
+
+impl Batch {
+    pub fn new() -> Batch {
+        Batch { ops: Vec::new() }
+    }
+    pub fn insert(&mut self, k: [u8; 32], t: u8, il: u32, b: Vec<u8>) {
+        self.ops.push(BatchOp::Insert(k, encode_value(t, il, &b)));
+    }
+    pub fn delete(&mut self, k: [u8; 32]) {
+        self.ops.push(BatchOp::Delete(k));
+    }
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+/// Key-value store where the MerkleTree keeps its nodes.
+///
+/// Implementors only deal with raw bytes, the node encoding (type, index
+/// length and node bytes) is handled by the provided `get` and `insert`.
+pub trait Storage {
+    fn get_raw(&mut self, k: &[u8; 32]) -> Result<Option<Vec<u8>>>;
+    fn insert_raw(&mut self, k: [u8; 32], v: Vec<u8>) -> Result<()>;
+    fn delete(&mut self, k: &[u8; 32]) -> Result<()>;
+    fn batch(&mut self, b: Batch) -> Result<()>;
+    /// All the keys in the storage.
+    fn keys(&mut self) -> Result<Vec<[u8; 32]>>;
+    /// The keys in the storage starting with `prefix`. Storages keeping the
+    /// keys sorted can list them without going through all the others.
+    fn keys_with_prefix(&mut self, prefix: &[u8]) -> Result<Vec<[u8; 32]>> {
+        let mut keys = self.keys()?;
+        keys.retain(|k| k.starts_with(prefix));
+        Ok(keys)
+    }
+
+    fn insert(&mut self, k: [u8; 32], t: u8, il: u32, b: Vec<u8>) -> Result<()> {
+        self.insert_raw(k, encode_value(t, il, &b))
+    }
+    fn get(&mut self, k: &[u8; 32]) -> Result<(u8, u32, Vec<u8>)> {
+        if *k == constants::EMPTYNODEVALUE {
+            return Ok((0, 0, constants::EMPTYNODEVALUE.to_vec()));
+        }
+        match self.get_raw(k)? {
+            Some(x) => decode_value(&x),
+            None => Ok((
+                constants::TYPENODEEMPTY,
+                0,
+                constants::EMPTYNODEVALUE.to_vec(),
+            )),
+        }
+    }
+}
+
+pub fn encode_value(t: u8, il: u32, b: &[u8]) -> Vec<u8> {
+    let mut v: Vec<u8> = vec![t];
+    v.extend(il.to_le_bytes().iter()); // il bytes are [u8;4] (4 bytes)
+    v.extend(b);
+    v
+}
+
+pub fn decode_value(x: &[u8]) -> Result<(u8, u32, Vec<u8>)> {
+    if x.len() < 5 {
+        return Err(Error::CorruptNode);
+    }
+    let t = x[0];
+    let il_bytes: [u8; 4] = [x[1], x[2], x[3], x[4]];
+    let il = u32::from_le_bytes(il_bytes);
+    if il as usize > x.len() - 5 {
+        return Err(Error::CorruptNode);
+    }
+    Ok((t, il, x[5..].to_vec()))
+}
 
 pub struct Db {
     storage: DB,
 }
 
 impl Db {
-    pub fn new(path: String, in_memory: bool) -> Db {
-        let opt: rusty_leveldb::Options;
-        if in_memory {
-            opt = rusty_leveldb::in_memory();
+    pub fn new(path: String, in_memory: bool) -> Result<Db> {
+        let opt: rusty_leveldb::Options = if in_memory {
+            rusty_leveldb::in_memory()
         } else {
-            opt = Default::default();
+            Default::default()
+        };
+        let database = DB::open(path, opt)?;
+        Ok(Db { storage: database })
+    }
+}
+
+impl Storage for Db {
+    fn get_raw(&mut self, k: &[u8; 32]) -> Result<Option<Vec<u8>>> {
+        Ok(self.storage.get(k))
+    }
+    fn insert_raw(&mut self, k: [u8; 32], v: Vec<u8>) -> Result<()> {
+        Ok(self.storage.put(&k[..], &v[..])?)
+    }
+    fn delete(&mut self, k: &[u8; 32]) -> Result<()> {
+        Ok(self.storage.delete(&k[..])?)
+    }
+    fn batch(&mut self, b: Batch) -> Result<()> {
+        let mut wb = WriteBatch::new();
+        for op in b.ops {
+            match op {
+                BatchOp::Insert(k, v) => wb.put(&k[..], &v[..]),
+                BatchOp::Delete(k) => wb.delete(&k[..]),
+            }
         }
-        let database = DB::open(path, opt).unwrap();
-        Db { storage: database }
+        Ok(self.storage.write(wb, false)?)
     }
-    pub fn insert(&mut self, k: [u8; 32], t: u8, il: u32, b: Vec<u8>) {
-        let mut v: Vec<u8>;
-        v = [t].to_vec();
-        let il_bytes = il.to_le_bytes();
-        v.extend(il_bytes.to_vec()); // il_bytes are [u8;4] (4 bytes)
-        v.extend(&b);
-        self.storage.put(&k[..], &v[..]).unwrap();
-    }
-    pub fn get(&mut self, k: &[u8; 32]) -> (u8, u32, Vec<u8>) {
-        if k.to_vec() == constants::EMPTYNODEVALUE.to_vec() {
-            return (0, 0, constants::EMPTYNODEVALUE.to_vec());
-        }
-        match self.storage.get(k) {
-            Some(x) => {
-                let t = x[0];
-                let il_bytes: [u8; 4] = [x[1], x[2], x[3], x[4]];
-                let il = u32::from_le_bytes(il_bytes);
-                let b = &x[5..];
-                (t, il, b.to_vec())
-            }
-            None => (
-                constants::TYPENODEEMPTY,
-                0,
-                constants::EMPTYNODEVALUE.to_vec(),
-            ),
+    fn keys(&mut self) -> Result<Vec<[u8; 32]>> {
+        let mut it = self.storage.new_iter()?;
+        let mut keys = Vec::new();
+        let (mut k, mut v) = (Vec::new(), Vec::new());
+        while it.advance() {
+            if it.current(&mut k, &mut v) && k.len() == 32 {
+                keys.push(*array_ref!(k, 0, 32));
+            }
+        }
+        Ok(keys)
+    }
+    fn keys_with_prefix(&mut self, prefix: &[u8]) -> Result<Vec<[u8; 32]>> {
+        let mut it = self.storage.new_iter()?;
+        let mut keys = Vec::new();
+        let (mut k, mut v) = (Vec::new(), Vec::new());
+        // the keys are sorted, the ones with the prefix go together
+        it.seek(prefix);
+        while it.current(&mut k, &mut v) && k.starts_with(prefix) {
+            if k.len() == 32 {
+                keys.push(*array_ref!(k, 0, 32));
+            }
+            it.advance();
+        }
+        Ok(keys)
+    }
+}
+
+/// Storage that keeps the nodes in a `HashMap`, without going through leveldb.
+/// Useful for tests and short-lived trees, nothing is persisted.
+#[derive(Default)]
+pub struct MemoryDb {
+    storage: HashMap<[u8; 32], Vec<u8>>,
+}
+
+impl MemoryDb {
+    pub fn new() -> MemoryDb {
+        MemoryDb {
+            storage: HashMap::new(),
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.storage.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.storage.is_empty()
+    }
+}
+
+impl Storage for MemoryDb {
+    fn get_raw(&mut self, k: &[u8; 32]) -> Result<Option<Vec<u8>>> {
+        Ok(self.storage.get(k).cloned())
+    }
+    fn insert_raw(&mut self, k: [u8; 32], v: Vec<u8>) -> Result<()> {
+        self.storage.insert(k, v);
+        Ok(())
+    }
+    fn delete(&mut self, k: &[u8; 32]) -> Result<()> {
+        self.storage.remove(k);
+        Ok(())
+    }
+    fn batch(&mut self, b: Batch) -> Result<()> {
+        for op in b.ops {
+            match op {
+                BatchOp::Insert(k, v) => self.storage.insert(k, v),
+                BatchOp::Delete(k) => self.storage.remove(&k),
+            };
+        }
+        Ok(())
+    }
+    fn keys(&mut self) -> Result<Vec<[u8; 32]>> {
+        Ok(self.storage.keys().cloned().collect())
+    }
+}
+
+/// Storage that keeps the writes in memory on top of another storage, without
+/// touching it until `commit`. Reads see the pending writes.
+pub struct Overlay<'a, S: Storage + ?Sized> {
+    sto: &'a mut S,
+    pending: HashMap<[u8; 32], Option<Vec<u8>>>,
+}
+
+impl<'a, S: Storage + ?Sized> Overlay<'a, S> {
+    pub fn new(sto: &'a mut S) -> Overlay<'a, S> {
+        Overlay {
+            sto,
+            pending: HashMap::new(),
         }
     }
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+    /// Writes all the pending writes to the underlying storage in one batch.
+    pub fn commit(self) -> Result<()> {
+        let mut b = Batch::new();
+        for (k, v) in self.pending {
+            b.ops.push(match v {
+                Some(v) => BatchOp::Insert(k, v),
+                None => BatchOp::Delete(k),
+            });
+        }
+        self.sto.batch(b)
+    }
+}
+
+impl<S: Storage + ?Sized> Storage for Overlay<'_, S> {
+    fn get_raw(&mut self, k: &[u8; 32]) -> Result<Option<Vec<u8>>> {
+        match self.pending.get(k) {
+            Some(v) => Ok(v.clone()),
+            None => self.sto.get_raw(k),
+        }
+    }
+    fn insert_raw(&mut self, k: [u8; 32], v: Vec<u8>) -> Result<()> {
+        self.pending.insert(k, Some(v));
+        Ok(())
+    }
+    fn delete(&mut self, k: &[u8; 32]) -> Result<()> {
+        self.pending.insert(*k, None);
+        Ok(())
+    }
+    fn batch(&mut self, b: Batch) -> Result<()> {
+        for op in b.ops {
+            match op {
+                BatchOp::Insert(k, v) => self.pending.insert(k, Some(v)),
+                BatchOp::Delete(k) => self.pending.insert(k, None),
+            };
+        }
+        Ok(())
+    }
+    fn keys(&mut self) -> Result<Vec<[u8; 32]>> {
+        let mut keys: Vec<[u8; 32]> = self
+            .sto
+            .keys()?
+            .into_iter()
+            .filter(|k| !self.pending.contains_key(k))
+            .collect();
+        for (k, v) in self.pending.iter() {
+            if v.is_some() {
+                keys.push(*k);
+            }
+        }
+        Ok(keys)
+    }
+    fn keys_with_prefix(&mut self, prefix: &[u8]) -> Result<Vec<[u8; 32]>> {
+        let mut keys: Vec<[u8; 32]> = self
+            .sto
+            .keys_with_prefix(prefix)?
+            .into_iter()
+            .filter(|k| !self.pending.contains_key(k))
+            .collect();
+        for (k, v) in self.pending.iter() {
+            if v.is_some() && k.starts_with(prefix) {
+                keys.push(*k);
+            }
+        }
+        Ok(keys)
+    }
+}
+
+// bytes of the hash of the id at the start of the keys of a namespace
+const NAMESPACE_PREFIX: usize = 8;
+
+/// Storage for a single tree inside a storage shared with other trees. The
+/// keys are mixed with the namespace id, and the records are tagged with it,
+/// so `keys` lists only the ones of the namespace (and `MerkleTree::prune`
+/// does not touch the nodes of other namespaces). The keys of a namespace
+/// share a prefix, `keys` only goes through them.
+pub struct Namespaced<'a, S: Storage + ?Sized> {
+    sto: &'a mut S,
+    id: [u8; 32],
+}
+
+impl<'a, S: Storage + ?Sized> Namespaced<'a, S> {
+    pub fn new(sto: &'a mut S, id: &[u8]) -> Namespaced<'a, S> {
+        Namespaced {
+            sto,
+            id: utils::hash_vec(id.to_vec()),
+        }
+    }
+    // the prefix of the namespace followed by the hash of the key
+    fn key(&self, k: &[u8; 32]) -> [u8; 32] {
+        let mut b = self.id.to_vec();
+        b.extend_from_slice(k);
+        let h = utils::hash_vec(b);
+        let mut key = [0; 32];
+        key[..NAMESPACE_PREFIX].copy_from_slice(&self.id[..NAMESPACE_PREFIX]);
+        key[NAMESPACE_PREFIX..].copy_from_slice(&h[..32 - NAMESPACE_PREFIX]);
+        key
+    }
+    fn tag(&self, k: &[u8; 32], v: Vec<u8>) -> Vec<u8> {
+        let mut b = self.id.to_vec();
+        b.extend_from_slice(k);
+        b.extend(v);
+        b
+    }
+}
+
+impl<S: Storage + ?Sized> Storage for Namespaced<'_, S> {
+    fn get_raw(&mut self, k: &[u8; 32]) -> Result<Option<Vec<u8>>> {
+        match self.sto.get_raw(&self.key(k))? {
+            Some(v) => {
+                if v.len() < 64 || v[..32] != self.id || v[32..64] != k[..] {
+                    return Err(Error::CorruptNode);
+                }
+                Ok(Some(v[64..].to_vec()))
+            }
+            None => Ok(None),
+        }
+    }
+    fn insert_raw(&mut self, k: [u8; 32], v: Vec<u8>) -> Result<()> {
+        let v = self.tag(&k, v);
+        self.sto.insert_raw(self.key(&k), v)
+    }
+    fn delete(&mut self, k: &[u8; 32]) -> Result<()> {
+        self.sto.delete(&self.key(k))
+    }
+    fn batch(&mut self, b: Batch) -> Result<()> {
+        let mut nb = Batch::new();
+        for op in b.ops {
+            nb.ops.push(match op {
+                BatchOp::Insert(k, v) => BatchOp::Insert(self.key(&k), self.tag(&k, v)),
+                BatchOp::Delete(k) => BatchOp::Delete(self.key(&k)),
+            });
+        }
+        self.sto.batch(nb)
+    }
+    fn keys(&mut self) -> Result<Vec<[u8; 32]>> {
+        let mut keys = Vec::new();
+        for k in self.sto.keys_with_prefix(&self.id[..NAMESPACE_PREFIX])? {
+            // the tag tells apart the namespaces with the same prefix
+            if let Some(v) = self.sto.get_raw(&k)? {
+                if v.len() >= 64 && v[..32] == self.id {
+                    keys.push(*array_ref!(v, 32, 32));
+                }
+            }
+        }
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_batch_and_delete(sto: &mut dyn Storage) {
+        let mut b = Batch::new();
+        b.insert([1; 32], constants::TYPENODEVALUE, 2, vec![5, 6, 7]);
+        b.insert([2; 32], constants::TYPENODENORMAL, 0, vec![8]);
+        b.delete([2; 32]);
+        sto.batch(b).unwrap();
+        assert_eq!(
+            (constants::TYPENODEVALUE, 2, vec![5, 6, 7]),
+            sto.get(&[1; 32]).unwrap()
+        );
+        assert_eq!(None, sto.get_raw(&[2; 32]).unwrap());
+
+        sto.delete(&[1; 32]).unwrap();
+        let (t, _, _) = sto.get(&[1; 32]).unwrap();
+        assert_eq!(constants::TYPENODEEMPTY, t);
+
+        sto.insert_raw([3; 32], vec![1, 2]).unwrap();
+        assert_eq!(Err(Error::CorruptNode), sto.get(&[3; 32]));
+        assert!(sto.keys().unwrap().contains(&[3; 32]));
+        sto.delete(&[3; 32]).unwrap();
+        assert!(!sto.keys().unwrap().contains(&[3; 32]));
+
+        // the keys with a prefix, next to others
+        let mut keys = Vec::new();
+        for i in 0..3 {
+            let mut k = [3; 32];
+            k[1] = i;
+            keys.push(k);
+        }
+        for k in keys.iter().chain(&[[2; 32], [4; 32]]) {
+            sto.insert_raw(*k, vec![1]).unwrap();
+        }
+        let mut with_prefix = sto.keys_with_prefix(&[3]).unwrap();
+        with_prefix.sort();
+        assert_eq!(keys, with_prefix);
+        assert_eq!(vec![keys[1]], sto.keys_with_prefix(&[3, 1]).unwrap());
+        assert!(sto.keys_with_prefix(&[3, 7]).unwrap().is_empty());
+        for k in keys.iter().chain(&[[2; 32], [4; 32]]) {
+            sto.delete(k).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_db_batch_and_delete() {
+        check_batch_and_delete(&mut Db::new("test".to_string(), true).unwrap());
+    }
+
+    #[test]
+    fn test_overlay() {
+        let mut sto = MemoryDb::new();
+        sto.insert([5; 32], constants::TYPENODENORMAL, 0, vec![5])
+            .unwrap();
+        sto.insert([6; 32], constants::TYPENODENORMAL, 0, vec![6])
+            .unwrap();
+
+        let mut overlay = Overlay::new(&mut sto);
+        check_batch_and_delete(&mut overlay);
+        overlay
+            .insert([4; 32], constants::TYPENODENORMAL, 0, vec![4])
+            .unwrap();
+        overlay.delete(&[6; 32]).unwrap();
+        assert_eq!(
+            (constants::TYPENODENORMAL, 0, vec![5]),
+            overlay.get(&[5; 32]).unwrap()
+        );
+        assert_eq!(None, overlay.get_raw(&[6; 32]).unwrap());
+        // dropping the overlay discards its writes
+        drop(overlay);
+        assert_eq!(2, sto.len());
+
+        let mut overlay = Overlay::new(&mut sto);
+        overlay
+            .insert([4; 32], constants::TYPENODENORMAL, 0, vec![4])
+            .unwrap();
+        overlay.delete(&[6; 32]).unwrap();
+        overlay.commit().unwrap();
+        assert_eq!(2, sto.len());
+        assert_eq!(None, sto.get_raw(&[6; 32]).unwrap());
+        assert_eq!(
+            (constants::TYPENODENORMAL, 0, vec![4]),
+            sto.get(&[4; 32]).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_namespaced() {
+        let mut sto = MemoryDb::new();
+        check_batch_and_delete(&mut Namespaced::new(&mut sto, b"a"));
+        assert!(sto.is_empty());
+
+        let mut ns_a = Namespaced::new(&mut sto, b"a");
+        ns_a.insert([1; 32], constants::TYPENODENORMAL, 0, vec![1])
+            .unwrap();
+        let mut ns_b = Namespaced::new(&mut sto, b"b");
+        ns_b.insert([1; 32], constants::TYPENODENORMAL, 0, vec![2])
+            .unwrap();
+        ns_b.insert([2; 32], constants::TYPENODENORMAL, 0, vec![3])
+            .unwrap();
+        assert_eq!(3, sto.len());
+        sto.insert([1; 32], constants::TYPENODENORMAL, 0, vec![4])
+            .unwrap();
+
+        let mut ns_a = Namespaced::new(&mut sto, b"a");
+        assert_eq!(
+            (constants::TYPENODENORMAL, 0, vec![1]),
+            ns_a.get(&[1; 32]).unwrap()
+        );
+        assert_eq!(None, ns_a.get_raw(&[2; 32]).unwrap());
+        assert_eq!(vec![[1; 32]], ns_a.keys().unwrap());
+        let mut ns_b = Namespaced::new(&mut sto, b"b");
+        let mut keys = ns_b.keys().unwrap();
+        keys.sort();
+        assert_eq!(vec![[1; 32], [2; 32]], keys);
+        ns_b.delete(&[1; 32]).unwrap();
+        assert_eq!(
+            (constants::TYPENODENORMAL, 0, vec![4]),
+            sto.get(&[1; 32]).unwrap()
+        );
+        assert_eq!(
+            (constants::TYPENODENORMAL, 0, vec![1]),
+            Namespaced::new(&mut sto, b"a").get(&[1; 32]).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_db_namespaced() {
+        let mut sto = Db::new("test".to_string(), true).unwrap();
+        for (id, n) in [(&b"a"[..], 3), (&b"b"[..], 5), (&b"c"[..], 1)] {
+            let mut ns = Namespaced::new(&mut sto, id);
+            for i in 0..n {
+                ns.insert([i; 32], constants::TYPENODENORMAL, 0, vec![i])
+                    .unwrap();
+            }
+        }
+        sto.insert([1; 32], constants::TYPENODENORMAL, 0, vec![1])
+            .unwrap();
+        assert_eq!(10, sto.keys().unwrap().len());
+        let mut keys = Namespaced::new(&mut sto, b"b").keys().unwrap();
+        keys.sort();
+        assert_eq!((0..5).map(|i| [i; 32]).collect::<Vec<_>>(), keys);
+        assert_eq!(
+            vec![[0; 32]],
+            Namespaced::new(&mut sto, b"c").keys().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_memory_db_batch_and_delete() {
+        let mut sto = MemoryDb::new();
+        check_batch_and_delete(&mut sto);
+        assert!(sto.is_empty());
+    }
 }
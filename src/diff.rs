@@ -0,0 +1,263 @@
+use std::cmp::Ordering;
+
+use super::constants;
+use super::db;
+use super::hasher::{Hasher, Keccak256};
+use super::iter::{LeafItem, Leaves};
+use super::node;
+use super::utils;
+use super::{Error, Result};
+
+/// Change of a leaf between two roots, see `diff`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Added(LeafItem),
+    Removed(LeafItem),
+    /// Leaf with the same hi and different value, before and after.
+    Changed(LeafItem, LeafItem),
+}
+
+/// Leaves added, removed and changed going from `root_a` to `root_b`, two
+/// roots of trees in the same storage, in path order.
+///
+/// Both trees are walked in lockstep, and the subtrees with the same hash in
+/// both of them are skipped, so the cost depends on the size of the changes
+/// and not on the size of the trees. A root that is not in the storage gives
+/// `Error::RootNotFound`, and a node missing under it `Error::CorruptNode`.
+pub fn diff<S: db::Storage + ?Sized>(
+    sto: &mut S,
+    root_a: [u8; 32],
+    root_b: [u8; 32],
+    num_levels: u32,
+) -> Result<Vec<Change>> {
+    diff_with_hasher::<Keccak256, S>(sto, root_a, root_b, num_levels)
+}
+
+/// Same as `diff`, for trees hashed with `H`.
+pub fn diff_with_hasher<H: Hasher, S: db::Storage + ?Sized>(
+    sto: &mut S,
+    root_a: [u8; 32],
+    root_b: [u8; 32],
+    num_levels: u32,
+) -> Result<Vec<Change>> {
+    if !(2..=257).contains(&num_levels) {
+        return Err(Error::InvalidNumLevels(num_levels));
+    }
+    for root in &[root_a, root_b] {
+        let (t, _, _) = sto.get(root)?;
+        if *root != constants::EMPTYNODEVALUE
+            && t != constants::TYPENODENORMAL
+            && !node::is_final(t)
+        {
+            return Err(Error::RootNotFound);
+        }
+    }
+    let mut changes = Vec::new();
+    diff_nodes::<H, S>(
+        sto,
+        num_levels,
+        (root_a, root_b),
+        num_levels - 1,
+        &mut Vec::new(),
+        &mut changes,
+    )?;
+    Ok(changes)
+}
+
+// diff of the subtrees of the nodes at level lvl, reached by path (top-down)
+fn diff_nodes<H: Hasher, S: db::Storage + ?Sized>(
+    sto: &mut S,
+    num_levels: u32,
+    nodes: ([u8; 32], [u8; 32]),
+    lvl: u32,
+    path: &mut Vec<bool>,
+    changes: &mut Vec<Change>,
+) -> Result<()> {
+    let (a, b) = nodes;
+    if a == b {
+        return Ok(());
+    }
+    let (ta, _, bytes_a) = sto.get(&a)?;
+    let (tb, _, bytes_b) = sto.get(&b)?;
+    // a node that is not empty must be in the storage
+    if (ta == constants::TYPENODEEMPTY && a != constants::EMPTYNODEVALUE)
+        || (tb == constants::TYPENODEEMPTY && b != constants::EMPTYNODEVALUE)
+    {
+        return Err(Error::CorruptNode);
+    }
+    if ta == constants::TYPENODENORMAL && tb == constants::TYPENODENORMAL && lvl > 0 {
+        let node_a = node::parse_node_bytes::<H>(bytes_a)?;
+        let node_b = node::parse_node_bytes::<H>(bytes_b)?;
+        path.push(false);
+        diff_nodes::<H, S>(
+            sto,
+            num_levels,
+            (node_a.child_l, node_b.child_l),
+            lvl - 1,
+            path,
+            changes,
+        )?;
+        path.pop();
+        path.push(true);
+        diff_nodes::<H, S>(
+            sto,
+            num_levels,
+            (node_a.child_r, node_b.child_r),
+            lvl - 1,
+            path,
+            changes,
+        )?;
+        path.pop();
+        return Ok(());
+    }
+
+    // one of the sides is a single leaf or empty, compare the leaves under
+    // both of them
+    let leaves_a = Leaves::<S, H>::subtree(sto, num_levels, a, lvl, path.clone())
+        .collect::<Result<Vec<LeafItem>>>()?;
+    let leaves_b = Leaves::<S, H>::subtree(sto, num_levels, b, lvl, path.clone())
+        .collect::<Result<Vec<LeafItem>>>()?;
+    let key = |l: &LeafItem| {
+        let mut path = utils::get_path(num_levels, l.0);
+        path.reverse();
+        (path, l.0)
+    };
+    let mut leaves_a = leaves_a.into_iter().peekable();
+    let mut leaves_b = leaves_b.into_iter().peekable();
+    loop {
+        let ord = match (leaves_a.peek(), leaves_b.peek()) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(la), Some(lb)) => key(la).cmp(&key(lb)),
+        };
+        match ord {
+            Ordering::Less => changes.push(Change::Removed(leaves_a.next().unwrap())),
+            Ordering::Greater => changes.push(Change::Added(leaves_b.next().unwrap())),
+            Ordering::Equal => {
+                let la = leaves_a.next().unwrap();
+                let lb = leaves_b.next().unwrap();
+                if la != lb {
+                    changes.push(Change::Changed(la, lb));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{val, val_with_hasher, vals};
+    use crate::{MerkleTree, Sha256, TestValue, Value};
+
+    fn item<V: Value>(v: &V) -> LeafItem {
+        (v.hi(), v.index_length(), v.bytes().clone())
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut sto = db::MemoryDb::new();
+        let mut mt = MerkleTree::new(&mut sto, 140).unwrap();
+        for i in 0..50 {
+            mt.add(&val(i)).unwrap();
+        }
+        let root_a = mt.get_root();
+        let updated = TestValue::new("3 this is a test updated".as_bytes().to_vec(), 15);
+        mt.update(&updated).unwrap();
+        mt.delete(val(7).hi()).unwrap();
+        mt.add(&val(50)).unwrap();
+        mt.add(&val(51)).unwrap();
+        let root_b = mt.get_root();
+
+        assert_eq!(0, diff(&mut sto, root_a, root_a, 140).unwrap().len());
+
+        let changes = diff(&mut sto, root_a, root_b, 140).unwrap();
+        assert_eq!(4, changes.len());
+        assert!(changes.contains(&Change::Changed(item(&val(3)), item(&updated))));
+        assert!(changes.contains(&Change::Removed(item(&val(7)))));
+        assert!(changes.contains(&Change::Added(item(&val(50)))));
+        assert!(changes.contains(&Change::Added(item(&val(51)))));
+
+        // the other way round
+        let changes_back = diff(&mut sto, root_b, root_a, 140).unwrap();
+        assert_eq!(4, changes_back.len());
+        assert!(changes_back.contains(&Change::Changed(item(&updated), item(&val(3)))));
+        assert!(changes_back.contains(&Change::Added(item(&val(7)))));
+        assert!(changes_back.contains(&Change::Removed(item(&val(50)))));
+
+        // from the empty tree, all the leaves in path order
+        let changes = diff(&mut sto, constants::EMPTYNODEVALUE, root_b, 140).unwrap();
+        let mut sto_b = db::MemoryDb::new();
+        let mut mt_b = MerkleTree::new(&mut sto_b, 140).unwrap();
+        for c in &changes {
+            match c {
+                Change::Added(l) => mt_b.add(&TestValue::new(l.2.clone(), l.1)),
+                _ => panic!("only additions expected"),
+            }
+            .unwrap();
+        }
+        assert_eq!(51, changes.len());
+        assert_eq!(root_b, mt_b.get_root());
+        let leaves: Vec<Change> = mt_b.iter().map(|l| Change::Added(l.unwrap())).collect();
+        assert_eq!(leaves, changes);
+    }
+
+    #[test]
+    fn test_diff_missing_node() {
+        let mut sto = db::MemoryDb::new();
+        let mut mt = MerkleTree::new(&mut sto, 140).unwrap();
+        mt.add_batch(&vals(20)).unwrap();
+        let root_a = mt.get_root();
+        mt.add(&val(20)).unwrap();
+        let root_b = mt.get_root();
+
+        // remove the child of root_b that changed
+        let node_a =
+            node::parse_node_bytes::<Keccak256>(db::Storage::get(&mut sto, &root_a).unwrap().2)
+                .unwrap();
+        let node_b =
+            node::parse_node_bytes::<Keccak256>(db::Storage::get(&mut sto, &root_b).unwrap().2)
+                .unwrap();
+        let child = if node_a.child_l != node_b.child_l {
+            node_b.child_l
+        } else {
+            node_b.child_r
+        };
+        db::Storage::delete(&mut sto, &child).unwrap();
+        assert_eq!(Err(Error::CorruptNode), diff(&mut sto, root_a, root_b, 140));
+    }
+
+    #[test]
+    fn test_diff_with_hasher() {
+        let mut sto = db::MemoryDb::new();
+        let mut mt = MerkleTree::<_, Sha256>::with_hasher(&mut sto, 140).unwrap();
+        mt.add(&val_with_hasher::<Sha256>(0)).unwrap();
+        let root_a = mt.get_root();
+        let v = val_with_hasher::<Sha256>(1);
+        mt.add(&v).unwrap();
+        let root_b = mt.get_root();
+        assert_eq!(
+            vec![Change::Added(item(&v))],
+            diff_with_hasher::<Sha256, _>(&mut sto, root_a, root_b, 140).unwrap()
+        );
+
+        // roots that are not in the storage
+        assert_eq!(
+            Err(Error::RootNotFound),
+            diff(&mut sto, [1; 32], root_b, 140)
+        );
+        assert_eq!(
+            Err(Error::RootNotFound),
+            diff(&mut sto, root_a, [1; 32], 140)
+        );
+
+        for num_levels in [0, 1, 258] {
+            assert_eq!(
+                Err(Error::InvalidNumLevels(num_levels)),
+                diff(&mut sto, root_a, root_b, num_levels)
+            );
+        }
+    }
+}
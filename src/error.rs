@@ -0,0 +1,83 @@
+#[cfg(feature = "std")]
+extern crate rusty_leveldb;
+
+use core::fmt;
+#[cfg(feature = "std")]
+use std::error;
+
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    KeyAlreadyExists,
+    KeyNotFound,
+    StorageError(String),
+    InvalidProof,
+    MalformedProof(ProofError),
+    InvalidNumLevels(u32),
+    CorruptNode,
+    ReadOnly,
+    RootNotFound,
+}
+
+#[cfg(feature = "std")]
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::KeyAlreadyExists => write!(f, "node already exists"),
+            Error::KeyNotFound => write!(f, "node does not exist"),
+            Error::StorageError(e) => write!(f, "storage error: {}", e),
+            Error::InvalidProof => write!(f, "invalid proof"),
+            Error::MalformedProof(e) => write!(f, "malformed proof: {}", e),
+            Error::InvalidNumLevels(n) => write!(f, "invalid number of levels: {}", n),
+            Error::CorruptNode => write!(f, "corrupt node in the storage"),
+            Error::ReadOnly => write!(f, "the tree is opened read only"),
+            Error::RootNotFound => write!(f, "root not found in the storage"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for Error {}
+
+/// Why the bytes of a proof can not be decoded, or the decoded proof does not
+/// fit in a tree.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProofError {
+    /// Shorter than the part of fixed size.
+    Truncated,
+    /// The length, which is not a multiple of the 32 bytes of a hash.
+    InvalidLength(usize),
+    /// The number of siblings marked in the bits and the one of siblings.
+    SiblingCountMismatch { bits: usize, siblings: usize },
+    /// The number of bytes after the end of the proof.
+    TrailingBytes(usize),
+    /// Depth of a sibling marked as not empty, deeper than the tree.
+    DepthOutOfRange(u32),
+}
+
+impl fmt::Display for ProofError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProofError::Truncated => write!(f, "truncated"),
+            ProofError::InvalidLength(n) => {
+                write!(f, "length {} is not a multiple of 32", n)
+            }
+            ProofError::SiblingCountMismatch { bits, siblings } => write!(
+                f,
+                "{} siblings marked as not empty, {} siblings",
+                bits, siblings
+            ),
+            ProofError::TrailingBytes(n) => write!(f, "{} trailing bytes", n),
+            ProofError::DepthOutOfRange(d) => {
+                write!(f, "sibling at depth {} out of the tree", d)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<rusty_leveldb::Status> for Error {
+    fn from(e: rusty_leveldb::Status) -> Error {
+        Error::StorageError(e.to_string())
+    }
+}
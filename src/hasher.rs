@@ -0,0 +1,205 @@
+extern crate blake2;
+extern crate sha2;
+
+use self::blake2::digest::consts::U32;
+use self::sha2::Digest;
+use tiny_keccak::Keccak;
+
+#[cfg(feature = "poseidon")]
+use ark_bn254::Fr;
+#[cfg(feature = "poseidon")]
+use ark_ff::{BigInteger, PrimeField};
+#[cfg(feature = "poseidon")]
+use light_poseidon::PoseidonHasher;
+
+/// Hash function used to compute the nodes of the tree.
+///
+/// `hash_node` is the hash of a node from the hashes of its two children, by
+/// default the hash of their concatenation.
+pub trait Hasher {
+    /// When true, a subtree holding a single leaf hashes to the hash of the
+    /// leaf, as in the iden3 sparse merkle trees. Otherwise it is the hash of
+    /// the branch going up from the leaf with empty siblings, which keeps the
+    /// root equal to the one of a non optimized tree.
+    const COMPACT_LEAVES: bool = false;
+
+    fn hash_bytes(b: &[u8]) -> [u8; 32];
+
+    fn hash_node(child_l: &[u8; 32], child_r: &[u8; 32]) -> [u8; 32] {
+        let mut b = [0; 64];
+        b[..32].copy_from_slice(child_l);
+        b[32..].copy_from_slice(child_r);
+        Self::hash_bytes(&b)
+    }
+}
+
+/// Keccak-256, the hash used by the Go version of the tree.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Keccak256;
+
+impl Hasher for Keccak256 {
+    fn hash_bytes(b: &[u8]) -> [u8; 32] {
+        let mut sha3 = Keccak::new_keccak256();
+        sha3.update(b);
+        let mut res: [u8; 32] = [0; 32];
+        sha3.finalize(&mut res);
+        res
+    }
+}
+
+/// SHA-256.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Sha256;
+
+impl Hasher for Sha256 {
+    fn hash_bytes(b: &[u8]) -> [u8; 32] {
+        sha2::Sha256::digest(b).into()
+    }
+}
+
+/// Blake2b with a 32 bytes output (not the truncated Blake2b-512).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Blake2b;
+
+impl Hasher for Blake2b {
+    fn hash_bytes(b: &[u8]) -> [u8; 32] {
+        blake2::Blake2b::<U32>::digest(b).into()
+    }
+}
+
+/// Poseidon over the BN254 scalar field, with the circom parameters.
+///
+/// Hashes are field elements encoded in 32 big-endian bytes, and so must be
+/// the keys (hi) of the values, bytes over the field modulus are reduced.
+/// Nodes are hashed as `poseidon(child_l, child_r)` and single leaf subtrees
+/// are compacted, so a tree whose values have `Poseidon::hash_leaf` as ht
+/// can be checked by the iden3 circom sparse merkle tree verifier.
+#[cfg(feature = "poseidon")]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Poseidon;
+
+#[cfg(feature = "poseidon")]
+impl Poseidon {
+    /// Max number of field elements of a single poseidon hash.
+    pub const MAX_INPUTS: usize = 12;
+
+    /// Hashes between 1 and `MAX_INPUTS` field elements.
+    pub fn hash_elements(inputs: &[[u8; 32]]) -> [u8; 32] {
+        let inputs: Vec<Fr> = inputs
+            .iter()
+            .map(|b| Fr::from_be_bytes_mod_order(b))
+            .collect();
+        let mut poseidon = light_poseidon::Poseidon::<Fr>::new_circom(inputs.len())
+            .expect("poseidon hash of 1 to 12 inputs");
+        let h = poseidon
+            .hash(&inputs)
+            .expect("poseidon hash of 1 to 12 inputs");
+        let b = h.into_bigint().to_bytes_be();
+        *array_ref!(b, 0, 32)
+    }
+
+    /// Hash of the leaf with the given key and value, `poseidon(key, value, 1)`.
+    pub fn hash_leaf(key: &[u8; 32], value: &[u8; 32]) -> [u8; 32] {
+        let mut one = [0; 32];
+        one[31] = 1;
+        Poseidon::hash_elements(&[*key, *value, one])
+    }
+}
+
+#[cfg(feature = "poseidon")]
+impl Hasher for Poseidon {
+    const COMPACT_LEAVES: bool = true;
+
+    // the bytes are split in chunks of 31 bytes, which always fit in a field
+    // element, and hashed in frames of MAX_INPUTS elements, each one after the
+    // first carrying the hash of the previous ones
+    fn hash_bytes(b: &[u8]) -> [u8; 32] {
+        let mut elements: Vec<[u8; 32]> = b
+            .chunks(31)
+            .map(|c| {
+                let mut e = [0; 32];
+                e[32 - c.len()..].copy_from_slice(c);
+                e
+            })
+            .collect();
+        if elements.is_empty() {
+            elements.push([0; 32]);
+        }
+        let first = elements.len().min(Poseidon::MAX_INPUTS);
+        let mut h = Poseidon::hash_elements(&elements[..first]);
+        for frame in elements[first..].chunks(Poseidon::MAX_INPUTS - 1) {
+            let mut inputs = vec![h];
+            inputs.extend_from_slice(frame);
+            h = Poseidon::hash_elements(&inputs);
+        }
+        h
+    }
+
+    fn hash_node(child_l: &[u8; 32], child_r: &[u8; 32]) -> [u8; 32] {
+        Poseidon::hash_elements(&[*child_l, *child_r])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustc_hex::ToHex;
+
+    #[test]
+    fn test_hash_bytes() {
+        let b = "test".as_bytes();
+        assert_eq!(
+            "9c22ff5f21f0b81b113e63f7db6da94fedef11b2119b4088b89664fb9a3cb658",
+            Keccak256::hash_bytes(b).to_hex()
+        );
+        assert_eq!(
+            "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08",
+            Sha256::hash_bytes(b).to_hex()
+        );
+        assert_eq!(
+            "928b20366943e2afd11ebc0eae2e53a93bf177a4fcf35bcc64d503704e65e202",
+            Blake2b::hash_bytes(b).to_hex()
+        );
+    }
+
+    #[test]
+    fn test_hash_node() {
+        let l = [1; 32];
+        let r = [2; 32];
+        let mut b = l.to_vec();
+        b.extend_from_slice(&r);
+        assert_eq!(Sha256::hash_bytes(&b), Sha256::hash_node(&l, &r));
+        assert_ne!(Sha256::hash_node(&l, &r), Sha256::hash_node(&r, &l));
+    }
+
+    #[cfg(feature = "poseidon")]
+    #[test]
+    fn test_poseidon() {
+        // test vectors of circomlib
+        let mut one = [0; 32];
+        one[31] = 1;
+        let mut two = [0; 32];
+        two[31] = 2;
+        assert_eq!(
+            "115cc0f5e7d690413df64c6b9662e9cf2a3617f2743245519e19607a4417189a",
+            Poseidon::hash_node(&one, &two).to_hex()
+        );
+        assert_eq!(
+            Poseidon::hash_elements(&[one, two, one]),
+            Poseidon::hash_leaf(&one, &two)
+        );
+
+        // bytes are packed in 31 bytes chunks
+        let b: Vec<u8> = (0..40).collect();
+        let mut c0 = [0; 32];
+        c0[1..].copy_from_slice(&b[..31]);
+        let mut c1 = [0; 32];
+        c1[23..].copy_from_slice(&b[31..]);
+        assert_eq!(Poseidon::hash_elements(&[c0, c1]), Poseidon::hash_bytes(&b));
+        let b: Vec<u8> = (0..=255).cycle().take(31 * 40).collect();
+        assert_ne!(
+            Poseidon::hash_bytes(&b),
+            Poseidon::hash_bytes(&b[..31 * 39])
+        );
+    }
+}
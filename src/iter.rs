@@ -0,0 +1,289 @@
+use std::marker::PhantomData;
+
+use super::constants;
+use super::db;
+use super::hasher::Hasher;
+use super::node;
+use super::utils;
+use super::{Error, Result};
+
+/// (hi, index_length, bytes) of a leaf.
+pub type LeafItem = ([u8; 32], u32, Vec<u8>);
+
+/// Iterator over the leaves of a `MerkleTree` in path order, yielding their
+/// (hi, index_length, bytes). Returned by `MerkleTree::iter`.
+///
+/// The leaves are read from the storage as the iteration goes, a storage
+/// error (or `Error::CorruptNode` for a node missing from the storage) is
+/// yielded once and ends the iteration.
+pub struct Leaves<'t, S: db::Storage + ?Sized, H: Hasher> {
+    sto: &'t mut S,
+    num_levels: u32,
+    // nodes still to visit, with their level and the path to them from the
+    // root (top-down), the next one on top
+    pending: Vec<([u8; 32], u32, Vec<bool>)>,
+    // only the leaves under this path (top-down) are yielded
+    prefix: Vec<bool>,
+    hasher: PhantomData<H>,
+}
+
+impl<'t, S: db::Storage + ?Sized, H: Hasher> Leaves<'t, S, H> {
+    pub(crate) fn new(sto: &'t mut S, num_levels: u32, root: [u8; 32]) -> Leaves<'t, S, H> {
+        Leaves::with_prefix(sto, num_levels, root, &[])
+    }
+
+    // path_prefix is in the order of utils::get_path, so its last bit is the
+    // one used at the root
+    pub(crate) fn with_prefix(
+        sto: &'t mut S,
+        num_levels: u32,
+        root: [u8; 32],
+        path_prefix: &[bool],
+    ) -> Leaves<'t, S, H> {
+        Leaves {
+            sto,
+            num_levels,
+            pending: vec![(root, num_levels - 1, Vec::new())],
+            prefix: path_prefix.iter().rev().cloned().collect(),
+            hasher: PhantomData,
+        }
+    }
+
+    // leaves of the subtree of node_hash, at level lvl and reached from the
+    // root by path (top-down)
+    pub(crate) fn subtree(
+        sto: &'t mut S,
+        num_levels: u32,
+        node_hash: [u8; 32],
+        lvl: u32,
+        path: Vec<bool>,
+    ) -> Leaves<'t, S, H> {
+        Leaves {
+            sto,
+            num_levels,
+            pending: vec![(node_hash, lvl, path)],
+            prefix: Vec::new(),
+            hasher: PhantomData,
+        }
+    }
+
+    // hi of a leaf stored as a bare value, which does not keep it, from the
+    // path to the leaf. Only the bits of hi used by the path are known.
+    fn hi_from_path(&self, path: &[bool]) -> [u8; 32] {
+        let mut hi = [0; 32];
+        // the bit i of hi is the one used at the level num_levels - 1 - i
+        for (i, bit) in path.iter().enumerate() {
+            if *bit {
+                hi[31 - i / 8] |= 1 << (i % 8);
+            }
+        }
+        hi
+    }
+
+    fn next_leaf(&mut self) -> Result<Option<LeafItem>> {
+        while let Some((node_hash, lvl, path)) = self.pending.pop() {
+            if node_hash == constants::EMPTYNODEVALUE {
+                continue;
+            }
+            let (t, il, node_bytes) = self.sto.get(&node_hash)?;
+            match t {
+                constants::TYPENODEFINAL | constants::TYPENODEFINALLEAF => {
+                    // a final node can be above the end of the prefix
+                    let leaf = node::parse_final_node_bytes::<H>(t, il, node_bytes)?;
+                    let mut path_leaf = utils::get_path(self.num_levels, leaf.hi);
+                    path_leaf.reverse();
+                    if path_leaf.starts_with(&self.prefix) {
+                        return Ok(Some((leaf.hi, leaf.index_length, leaf.bytes)));
+                    }
+                }
+                constants::TYPENODEVALUE => {
+                    // above the end of the prefix the hi of the leaf is not
+                    // known, it can not be under the prefix
+                    if path.len() >= self.prefix.len() {
+                        return Ok(Some((self.hi_from_path(&path), il, node_bytes)));
+                    }
+                }
+                constants::TYPENODENORMAL if lvl > 0 => {
+                    let node = node::parse_node_bytes::<H>(node_bytes)?;
+                    // until the end of the prefix, go down only its way
+                    let next = self.prefix.get(path.len()).cloned();
+                    if next != Some(false) {
+                        let mut path_r = path.clone();
+                        path_r.push(true);
+                        self.pending.push((node.child_r, lvl - 1, path_r));
+                    }
+                    if next != Some(true) {
+                        let mut path_l = path;
+                        path_l.push(false);
+                        self.pending.push((node.child_l, lvl - 1, path_l));
+                    }
+                }
+                // a node missing from the storage, or of a type that can
+                // not be there
+                _ => return Err(Error::CorruptNode),
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<'t, S: db::Storage + ?Sized, H: Hasher> Iterator for Leaves<'t, S, H> {
+    type Item = Result<LeafItem>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_leaf() {
+            Ok(leaf) => leaf.map(Ok),
+            Err(e) => {
+                self.pending.clear();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{val, vals};
+    use crate::{MerkleTree, TestValue, Value};
+
+    fn path_order(num_levels: u32, vs: &mut [TestValue]) {
+        // path order is the order of the reversed paths, from the root down
+        vs.sort_by_key(|v| {
+            let mut path = utils::get_path(num_levels, v.hi());
+            path.reverse();
+            path
+        });
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut sto = db::MemoryDb::new();
+        let mut mt = MerkleTree::new(&mut sto, 140).unwrap();
+        assert_eq!(0, mt.iter().count());
+
+        let mut vs = vals(50);
+        for v in &vs {
+            mt.add(v).unwrap();
+        }
+        mt.delete(vs.remove(7).hi()).unwrap();
+        path_order(140, &mut vs);
+        let leaves: Vec<LeafItem> = mt.iter().map(|l| l.unwrap()).collect();
+        let expected: Vec<LeafItem> = vs
+            .iter()
+            .map(|v| (v.hi(), v.index_length(), v.bytes().clone()))
+            .collect();
+        assert_eq!(expected, leaves);
+
+        // a single leaf is a final node at the root
+        let mut sto = db::MemoryDb::new();
+        let mut mt = MerkleTree::new(&mut sto, 140).unwrap();
+        mt.add(&val(1)).unwrap();
+        let leaves: Vec<LeafItem> = mt.iter().map(|l| l.unwrap()).collect();
+        assert_eq!(vec![(val(1).hi(), 15, val(1).bytes)], leaves);
+    }
+
+    #[test]
+    fn test_iter_full_depth() {
+        // with 3 levels every leaf goes down to the bottom level
+        let mut sto = db::MemoryDb::new();
+        let mut mt = MerkleTree::new(&mut sto, 3).unwrap();
+        let mut vs: Vec<TestValue> = Vec::new();
+        let mut i = 0;
+        while vs.len() < 4 {
+            let v = val(i);
+            if mt.add(&v).is_ok() {
+                vs.push(v);
+            }
+            i += 1;
+        }
+        path_order(3, &mut vs);
+        let his: Vec<[u8; 32]> = mt.iter().map(|l| l.unwrap().0).collect();
+        assert_eq!(vs.iter().map(|v| v.hi()).collect::<Vec<_>>(), his);
+    }
+
+    #[test]
+    fn test_iter_prefix() {
+        let mut sto = db::MemoryDb::new();
+        let mut mt = MerkleTree::new(&mut sto, 140).unwrap();
+        let mut vs = vals(100);
+        mt.add_batch(&vs).unwrap();
+        path_order(140, &mut vs);
+
+        let under = |vs: &[TestValue], prefix: &[bool]| -> Vec<[u8; 32]> {
+            vs.iter()
+                .map(|v| v.hi())
+                .filter(|hi| utils::get_path(140, *hi).ends_with(prefix))
+                .collect()
+        };
+        let mut total = 0;
+        for prefix in [
+            vec![false],
+            vec![true],
+            vec![false, true],
+            vec![true, false, true],
+            vec![true, true, false, false, true, true],
+        ] {
+            let his: Vec<[u8; 32]> = mt.iter_prefix(&prefix).map(|l| l.unwrap().0).collect();
+            assert_eq!(under(&vs, &prefix), his);
+            if prefix.len() == 1 {
+                total += his.len();
+            }
+        }
+        assert_eq!(100, total);
+        assert_eq!(100, mt.iter_prefix(&[]).count());
+
+        // the whole path of a leaf, down to a final node above the bottom
+        let path = utils::get_path(140, vs[3].hi());
+        let leaves: Vec<LeafItem> = mt.iter_prefix(&path).map(|l| l.unwrap()).collect();
+        assert_eq!(vec![(vs[3].hi(), 15, vs[3].bytes.clone())], leaves);
+        let mut path = path[100..].to_vec();
+        path[0] = !path[0];
+        assert_eq!(under(&vs, &path).len(), mt.iter_prefix(&path).count());
+        assert_eq!(0, mt.iter_prefix(&[false; 140]).count());
+    }
+
+    #[test]
+    fn test_iter_value_leaf() {
+        // bare value leaves only know the bits of hi in their path
+        let mut sto = db::MemoryDb::new();
+        let l = [5; 32];
+        let r = [6; 32];
+        let n = node::TreeNode::new(l, r);
+        db::Storage::insert(&mut sto, n.ht(), constants::TYPENODENORMAL, 0, n.bytes()).unwrap();
+        db::Storage::insert(&mut sto, l, constants::TYPENODEVALUE, 1, vec![1]).unwrap();
+        db::Storage::insert(&mut sto, r, constants::TYPENODEVALUE, 1, vec![2]).unwrap();
+        let mut leaves = Leaves::<_, crate::Keccak256>::new(&mut sto, 2, n.ht());
+        assert_eq!(([0; 32], 1, vec![1]), leaves.next().unwrap().unwrap());
+        let mut hi = [0; 32];
+        hi[31] = 1;
+        assert_eq!((hi, 1, vec![2]), leaves.next().unwrap().unwrap());
+        assert!(leaves.next().is_none());
+    }
+
+    #[test]
+    fn test_iter_missing_node() {
+        let mut sto = db::MemoryDb::new();
+        let mut mt = MerkleTree::new(&mut sto, 140).unwrap();
+        mt.add_batch(&vals(20)).unwrap();
+        let root = mt.get_root();
+        let (_, _, b) = db::Storage::get(&mut sto, &root).unwrap();
+        let n = node::parse_node_bytes::<crate::Keccak256>(b).unwrap();
+        db::Storage::delete(&mut sto, &n.child_l).unwrap();
+
+        let mut mt = MerkleTree::new(&mut sto, 140).unwrap();
+        let leaves: Vec<Result<LeafItem>> = mt.iter().collect();
+        assert_eq!(Some(&Err(Error::CorruptNode)), leaves.last());
+        assert!(leaves[..leaves.len() - 1].iter().all(|l| l.is_ok()));
+        assert!(leaves.len() <= 20);
+    }
+
+    #[test]
+    fn test_iter_error() {
+        let mut sto = db::MemoryDb::new();
+        db::Storage::insert_raw(&mut sto, [7; 32], vec![1]).unwrap();
+        let mut leaves = Leaves::<_, crate::Keccak256>::new(&mut sto, 140, [7; 32]);
+        assert!(leaves.next().unwrap().is_err());
+        assert!(leaves.next().is_none());
+    }
+}
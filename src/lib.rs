@@ -1,29 +1,103 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
 #[macro_use]
 extern crate arrayref;
+#[cfg(feature = "poseidon")]
+extern crate ark_bn254;
+#[cfg(feature = "poseidon")]
+extern crate ark_ff;
+#[cfg(test)]
 extern crate hex;
+#[cfg(feature = "poseidon")]
+extern crate light_poseidon;
+#[cfg(feature = "std")]
 extern crate rustc_hex;
 extern crate tiny_keccak;
 
+#[cfg(feature = "std")]
 use rustc_hex::ToHex;
+#[cfg(feature = "std")]
+use std::collections::{HashMap, HashSet};
+#[cfg(feature = "std")]
+use std::marker::PhantomData;
+#[cfg(feature = "std")]
+use std::thread;
+#[cfg(feature = "std")]
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub mod constants;
+#[cfg(feature = "std")]
 pub mod db;
+#[cfg(feature = "std")]
+pub mod diff;
+mod error;
+pub mod hasher;
+#[cfg(feature = "std")]
+pub mod iter;
 pub mod node;
+pub mod proof;
+#[cfg(feature = "std")]
+pub mod transaction;
 pub mod utils;
 
-type Result<T> = std::result::Result<T, String>;
+#[cfg(feature = "std")]
+pub use diff::{diff, diff_with_hasher, Change};
+#[cfg(feature = "std")]
+pub use error::Error;
+pub use error::ProofError;
+#[cfg(feature = "poseidon")]
+pub use hasher::Poseidon;
+pub use hasher::{Blake2b, Hasher, Keccak256, Sha256};
+#[cfg(feature = "std")]
+pub use iter::Leaves;
+#[cfg(feature = "std")]
+pub use proof::{
+    verify_multi_proof, verify_multi_proof_with_hasher, verify_proof, verify_proof_with_hasher,
+    verify_transition, verify_transition_with_hasher, verify_value_proof, MerkleProof, MultiProof,
+    TransitionProof,
+};
+pub use proof::{verify_proof_view, verify_proof_view_with_hasher, NodeAux, ProofView};
+#[cfg(feature = "std")]
+pub use transaction::Transaction;
 
-pub struct TestValue {
+#[cfg(feature = "std")]
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(feature = "std")]
+pub struct TestValue<H: Hasher = Keccak256> {
     pub bytes: Vec<u8>,
     pub index_length: u32,
+    hasher: PhantomData<H>,
+}
+
+#[cfg(feature = "std")]
+impl TestValue {
+    pub fn new(bytes: Vec<u8>, index_length: u32) -> TestValue {
+        TestValue::with_hasher(bytes, index_length)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<H: Hasher> TestValue<H> {
+    /// Builds a value whose hi and ht are computed with `H`.
+    pub fn with_hasher(bytes: Vec<u8>, index_length: u32) -> TestValue<H> {
+        TestValue {
+            bytes,
+            index_length,
+            hasher: PhantomData,
+        }
+    }
 }
+
+#[cfg(feature = "std")]
 pub trait Value {
     fn bytes(&self) -> &Vec<u8>;
     fn index_length(&self) -> u32;
     fn hi(&self) -> [u8; 32];
     fn ht(&self) -> [u8; 32];
 }
-impl Value for TestValue {
+#[cfg(feature = "std")]
+impl<H: Hasher> Value for TestValue<H> {
     fn bytes(&self) -> &Vec<u8> {
         &self.bytes
     }
@@ -31,38 +105,142 @@ impl Value for TestValue {
         self.index_length
     }
     fn hi(&self) -> [u8; 32] {
-        utils::hash_vec(
-            self.bytes()
-                .to_vec()
-                .split_at(self.index_length() as usize)
-                .0
-                .to_vec(),
-        )
+        H::hash_bytes(self.bytes().split_at(self.index_length() as usize).0)
     }
     fn ht(&self) -> [u8; 32] {
-        utils::hash_vec(self.bytes().to_vec())
+        H::hash_bytes(self.bytes())
     }
 }
 
-pub struct MerkleTree<'a> {
+/// Root committed to the storage, see `MerkleTree::roots`.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct RootEntry {
+    pub seq: u64,
+    pub root: [u8; 32],
+    /// Seconds since the unix epoch.
+    pub timestamp: u64,
+}
+
+/// Counts of the nodes of a tree, see `MerkleTree::stats`. The depth of a
+/// node is its distance to the root, 0 for the root.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TreeStats {
+    pub leaves: usize,
+    pub normal_nodes: usize,
+    pub final_nodes: usize,
+    /// Values stored next to the nodes, by the ht of their leaf.
+    pub value_nodes: usize,
+    pub max_final_depth: u32,
+    pub avg_final_depth: f64,
+    /// Keys in the storage, including the ones of other roots.
+    pub store_keys: usize,
+    pub store_bytes: usize,
+}
+
+#[cfg(feature = "std")]
+pub struct MerkleTree<'a, S: db::Storage + ?Sized, H: Hasher = Keccak256> {
     root: [u8; 32],
     num_levels: u32,
-    sto: &'a mut db::Db,
-    root_node_key: [u8;32],
+    sto: &'a mut S,
+    root_node_key: [u8; 32],
+    roots_key: [u8; 32],
+    read_only: bool,
+    // false for the trees of a transaction, which adds a single entry to the
+    // list of roots when it is committed
+    record_roots: bool,
+    hasher: PhantomData<H>,
 }
-impl<'a> MerkleTree<'a> {
-    pub fn new(database: &'a mut db::Db, num_levels: u32) -> MerkleTree<'a> {
-        let rnk: [u8; 32] = utils::hash_vec("root".as_bytes().to_vec());
-        let (_, _, root_vec) = database.get(&rnk);
-        let mut root: [u8; 32] = [0;32];
-        root.copy_from_slice(&root_vec); // root will be [0;32] if not found (EMPTYNODEVALUE)
-
-        MerkleTree {
-            root: root,
+
+#[cfg(feature = "std")]
+impl<'a, S: db::Storage + ?Sized> MerkleTree<'a, S> {
+    pub fn new(database: &'a mut S, num_levels: u32) -> Result<MerkleTree<'a, S>> {
+        MerkleTree::with_hasher(database, num_levels)
+    }
+    /// Opens the tree named `id`, so several trees can share a storage, each
+    /// one with its own root. The nodes are still shared between the trees,
+    /// to keep them apart too (and `prune` a tree without keeping the nodes
+    /// of the others) use a `db::Namespaced` storage for each tree. Both kinds
+    /// of trees can be mixed on the same storage.
+    pub fn with_id(database: &'a mut S, num_levels: u32, id: &[u8]) -> Result<MerkleTree<'a, S>> {
+        MerkleTree::with_id_and_hasher(database, num_levels, id)
+    }
+    /// Opens a read only view of the tree at the given (current or past) root.
+    pub fn at_root(
+        database: &'a mut S,
+        root: [u8; 32],
+        num_levels: u32,
+    ) -> Result<MerkleTree<'a, S>> {
+        MerkleTree::at_root_with_hasher(database, root, num_levels)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, S: db::Storage + ?Sized, H: Hasher> MerkleTree<'a, S, H> {
+    /// Opens the tree hashing its nodes with `H` instead of the default
+    /// Keccak-256. The same hasher must be used every time the tree is opened.
+    pub fn with_hasher(database: &'a mut S, num_levels: u32) -> Result<MerkleTree<'a, S, H>> {
+        MerkleTree::with_id_and_hasher(database, num_levels, &[])
+    }
+    /// Same as `with_id`, for a tree hashed with `H`.
+    pub fn with_id_and_hasher(
+        database: &'a mut S,
+        num_levels: u32,
+        id: &[u8],
+    ) -> Result<MerkleTree<'a, S, H>> {
+        // the path of a leaf uses the bits of a 32 bytes hash, and the proofs
+        // have 32 bytes to mark the non empty siblings
+        if !(2..=257).contains(&num_levels) {
+            return Err(Error::InvalidNumLevels(num_levels));
+        }
+        let rnk = Self::tree_key("root", id);
+        // root will be [0;32] if not found (EMPTYNODEVALUE)
+        let (t, _, root_vec) = database.get(&rnk)?;
+        if t != constants::TYPENODEEMPTY && (t != constants::TYPENODEROOT || root_vec.len() != 32) {
+            return Err(Error::CorruptNode);
+        }
+        let root = *array_ref!(root_vec, 0, 32);
+
+        Ok(MerkleTree {
+            root,
             num_levels,
             sto: database,
             root_node_key: rnk,
+            roots_key: Self::tree_key("roots", id),
+            read_only: false,
+            record_roots: true,
+            hasher: PhantomData,
+        })
+    }
+    // key of the tree named id, the unnamed tree keeps the keys it always
+    // had. The id goes after a separator and its length, so the keys of two
+    // trees (or of a tree and the word) can not be the same
+    fn tree_key(word: &str, id: &[u8]) -> [u8; 32] {
+        let mut b = word.as_bytes().to_vec();
+        if !id.is_empty() {
+            b.push(0);
+            b.extend((id.len() as u32).to_le_bytes().iter());
+            b.extend_from_slice(id);
         }
+        utils::hash_vec(b)
+    }
+    /// Same as `at_root`, for a tree hashed with `H`.
+    pub fn at_root_with_hasher(
+        database: &'a mut S,
+        root: [u8; 32],
+        num_levels: u32,
+    ) -> Result<MerkleTree<'a, S, H>> {
+        let mut mt = MerkleTree::with_hasher(database, num_levels)?;
+        if root != constants::EMPTYNODEVALUE {
+            let (t, _, _) = mt.sto.get(&root)?;
+            if t != constants::TYPENODENORMAL && !node::is_final(t) {
+                return Err(Error::RootNotFound);
+            }
+        }
+        mt.root = root;
+        mt.read_only = true;
+        Ok(mt)
     }
     pub fn get_root(&mut self) -> [u8; 32] {
         self.root
@@ -71,9 +249,16 @@ impl<'a> MerkleTree<'a> {
         self.num_levels
     }
 
-    pub fn add(&mut self, v: &TestValue) -> Result<()> {
+    /// Starts a transaction over the current root, see `Transaction`.
+    pub fn transaction(&mut self) -> Transaction<'_, S, H> {
+        Transaction::new(self)
+    }
+
+    pub fn add<V: Value>(&mut self, v: &V) -> Result<()> {
+        let leaf = node::Leaf::from_value(v);
+        let mut batch = db::Batch::new();
         // add the leaf that we are adding
-        self.sto.insert(
+        batch.insert(
             v.ht(),
             constants::TYPENODEVALUE,
             v.index_length(),
@@ -88,48 +273,40 @@ impl<'a> MerkleTree<'a> {
 
         for i in (0..=self.num_levels - 2).rev() {
             // get node
-            let (t, il, node_bytes) = self.sto.get(&node_hash);
-            if t == constants::TYPENODEFINAL {
-                let hi_child =
-                    utils::hash_vec(node_bytes.to_vec().split_at(il as usize).0.to_vec());
-                let path_child = utils::get_path(self.num_levels, hi_child);
+            let (t, il, node_bytes) = self.sto.get(&node_hash)?;
+            if node::is_final(t) {
+                let leaf_child = node::parse_final_node_bytes::<H>(t, il, node_bytes)?;
+                let path_child = utils::get_path(self.num_levels, leaf_child.hi);
                 let pos_diff = utils::compare_paths(&path_child, &path);
                 if pos_diff == -1 {
-                    return Err("node already exists".to_owned());
+                    return Err(Error::KeyAlreadyExists);
                 }
-                let final_node_1_hash = utils::calc_hash_from_leaf_and_level(
+                let final_node_1_hash = utils::calc_hash_from_leaf_and_level::<H>(
                     pos_diff as u32,
                     &path_child,
-                    utils::hash_vec(node_bytes.to_vec()),
+                    leaf_child.ht,
                 );
-                self.sto.insert(
+                batch.insert(
                     final_node_1_hash,
-                    constants::TYPENODEFINAL,
+                    constants::TYPENODEFINALLEAF,
                     il,
-                    node_bytes.to_vec(),
+                    leaf_child.final_node_bytes(),
                 );
                 let final_node_2_hash =
-                    utils::calc_hash_from_leaf_and_level(pos_diff as u32, &path, v.ht());
-                self.sto.insert(
+                    utils::calc_hash_from_leaf_and_level::<H>(pos_diff as u32, &path, v.ht());
+                batch.insert(
                     final_node_2_hash,
-                    constants::TYPENODEFINAL,
+                    constants::TYPENODEFINALLEAF,
                     v.index_length(),
-                    v.bytes().to_vec(),
+                    leaf.final_node_bytes(),
                 );
 
                 // parent node
-                let parent_node: node::TreeNode;
-                if path[pos_diff as usize] {
-                    parent_node = node::TreeNode {
-                        child_l: final_node_1_hash,
-                        child_r: final_node_2_hash,
-                    }
+                let parent_node = if path[pos_diff as usize] {
+                    node::TreeNode::<H>::with_hasher(final_node_1_hash, final_node_2_hash)
                 } else {
-                    parent_node = node::TreeNode {
-                        child_l: final_node_2_hash,
-                        child_r: final_node_1_hash,
-                    }
-                }
+                    node::TreeNode::<H>::with_hasher(final_node_2_hash, final_node_1_hash)
+                };
                 let empties = utils::get_empties_between_i_and_pos(i, pos_diff as u32 + 1);
                 for empty in &empties {
                     siblings.push(*empty);
@@ -137,7 +314,8 @@ impl<'a> MerkleTree<'a> {
 
                 let path_from_pos_diff = utils::cut_path(&path, (pos_diff + 1) as usize);
 
-                self.root = self.replace_leaf(
+                let root = Self::replace_leaf(
+                    &mut batch,
                     path_from_pos_diff,
                     &siblings,
                     parent_node.ht(),
@@ -145,12 +323,11 @@ impl<'a> MerkleTree<'a> {
                     0,
                     parent_node.bytes().to_vec(),
                 );
-                self.sto
-                    .insert(self.root_node_key, constants::TYPENODEROOT, 0, self.root.to_vec());
+                self.commit(batch, root)?;
                 return Ok(());
             }
 
-            let node = node::parse_node_bytes(node_bytes);
+            let node = node::parse_node_bytes::<H>(node_bytes)?;
 
             let sibling: [u8; 32];
             if !path[i as usize] {
@@ -166,146 +343,780 @@ impl<'a> MerkleTree<'a> {
                     && siblings[siblings.len() - 1] == constants::EMPTYNODEVALUE
                 {
                     let final_node_hash =
-                        utils::calc_hash_from_leaf_and_level(i + 1, &path, v.ht());
-                    self.sto.insert(
+                        utils::calc_hash_from_leaf_and_level::<H>(i + 1, &path, v.ht());
+                    batch.insert(
                         final_node_hash,
-                        constants::TYPENODEFINAL,
+                        constants::TYPENODEFINALLEAF,
                         v.index_length(),
-                        v.bytes().to_vec(),
+                        leaf.final_node_bytes(),
                     );
-                    self.root = final_node_hash;
-                    self.sto
-                        .insert(self.root_node_key, constants::TYPENODEROOT, 0, self.root.to_vec());
+                    self.commit(batch, final_node_hash)?;
                     return Ok(());
                 }
-                let final_node_hash = utils::calc_hash_from_leaf_and_level(i, &path, v.ht());
+                let final_node_hash = utils::calc_hash_from_leaf_and_level::<H>(i, &path, v.ht());
                 let path_from_i = utils::cut_path(&path, i as usize);
-                self.root = self.replace_leaf(
+                let root = Self::replace_leaf(
+                    &mut batch,
                     path_from_i,
                     &siblings,
                     final_node_hash,
-                    constants::TYPENODEFINAL,
+                    constants::TYPENODEFINALLEAF,
                     v.index_length(),
-                    v.bytes().to_vec(),
+                    leaf.final_node_bytes(),
                 );
-                self.sto
-                    .insert(self.root_node_key, constants::TYPENODEROOT, 0, self.root.to_vec());
+                self.commit(batch, root)?;
                 return Ok(());
             }
         }
-        self.root = self.replace_leaf(
-            path,
-            &siblings,
+        // the whole path is taken by a leaf at the bottom level
+        Err(Error::KeyAlreadyExists)
+    }
+
+    /// Adds the value, returning the proof that the tree went from the
+    /// previous root to the new one, to be checked with `verify_transition`.
+    pub fn add_with_proof<V: Value>(&mut self, v: &V) -> Result<TransitionProof> {
+        let old = self.generate_non_existence_proof(v.hi())?;
+        self.add(v)?;
+        let new = self.generate_proof(v.hi())?;
+        Ok(TransitionProof { old, new })
+    }
+
+    /// Adds all the values at once, giving the same root as adding them one by
+    /// one. The leaves are sorted by path and the affected subtrees are built
+    /// bottom-up, so each node is hashed and written only once.
+    pub fn add_batch<V: Value>(&mut self, vs: &[V]) -> Result<()> {
+        if vs.is_empty() {
+            return Ok(());
+        }
+        let mut batch = db::Batch::new();
+        let leaves = self.sorted_leaves(&mut batch, vs)?;
+        let root = self.add_leaves(&mut batch, self.root, self.num_levels - 1, &leaves)?;
+        self.commit(batch, root)
+    }
+
+    /// Same as `add_batch`, building in parallel the subtrees under the top
+    /// `k` levels, spread over as many threads as
+    /// `std::thread::available_parallelism`. Only the import into an empty
+    /// tree is parallelized, otherwise it falls back to `add_batch`.
+    pub fn add_batch_parallel<V: Value>(&mut self, vs: &[V], k: u32) -> Result<()> {
+        if self.root != constants::EMPTYNODEVALUE {
+            return self.add_batch(vs);
+        }
+        if vs.is_empty() {
+            return Ok(());
+        }
+        let mut batch = db::Batch::new();
+        let leaves = self.sorted_leaves(&mut batch, vs)?;
+
+        // the subtrees at the partition level with more than one leaf, those
+        // with a single one end up as a final node higher in the tree
+        let part_lvl = self.num_levels - 1 - k.min(self.num_levels - 1);
+        let mut parts: Vec<(usize, usize)> = Vec::new();
+        let mut start = 0;
+        for end in 1..=leaves.len() {
+            if end == leaves.len()
+                || leaves[end].0[part_lvl as usize..] != leaves[start].0[part_lvl as usize..]
+            {
+                if end - start > 1 {
+                    parts.push((start, end));
+                }
+                start = end;
+            }
+        }
+
+        // as many threads as the machine runs in parallel, each one building
+        // a chunk of consecutive subtrees
+        let workers = thread::available_parallelism().map_or(1, |n| n.get());
+        let chunk_len = parts.len().div_ceil(workers).max(1);
+        let built: Vec<Result<([u8; 32], db::Batch)>> = thread::scope(|s| {
+            let handles: Vec<_> = parts
+                .chunks(chunk_len)
+                .map(|chunk| {
+                    let leaves = &leaves;
+                    s.spawn(move || {
+                        chunk
+                            .iter()
+                            .map(|&(start, end)| {
+                                let mut batch = db::Batch::new();
+                                let hash =
+                                    Self::build_subtree(&mut batch, part_lvl, &leaves[start..end])?;
+                                Ok((hash, batch))
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|h| h.join().expect("subtree builder thread panicked"))
+                .collect()
+        });
+        let mut subtrees = HashMap::new();
+        for (&(start, _), b) in parts.iter().zip(built) {
+            let (hash, b) = b?;
+            batch.ops.extend(b.ops);
+            subtrees.insert(start, hash);
+        }
+
+        let root = Self::merge_subtrees(
+            &mut batch,
+            self.num_levels - 1,
+            part_lvl,
+            &leaves,
+            0,
+            &subtrees,
+        )?;
+        self.commit(batch, root)
+    }
+
+    // stores the values of the leaves, returning them sorted from the root
+    // down so the leaves of each subtree are together
+    fn sorted_leaves<V: Value>(
+        &self,
+        batch: &mut db::Batch,
+        vs: &[V],
+    ) -> Result<Vec<(Vec<bool>, node::Leaf)>> {
+        let mut leaves: Vec<(Vec<bool>, node::Leaf)> = Vec::new();
+        for v in vs {
+            batch.insert(
+                v.ht(),
+                constants::TYPENODEVALUE,
+                v.index_length(),
+                v.bytes().to_vec(),
+            );
+            let leaf = node::Leaf::from_value(v);
+            leaves.push((utils::get_path(self.num_levels, leaf.hi), leaf));
+        }
+        leaves.sort_by(|a, b| a.0.iter().rev().cmp(b.0.iter().rev()));
+        if leaves.windows(2).any(|w| w[0].0 == w[1].0) {
+            return Err(Error::KeyAlreadyExists);
+        }
+        Ok(leaves)
+    }
+
+    // adds the leaves (sorted, and all of them under node_hash) to the subtree
+    // of node_hash at level lvl, returning the new hash of the subtree
+    fn add_leaves(
+        &mut self,
+        batch: &mut db::Batch,
+        node_hash: [u8; 32],
+        lvl: u32,
+        leaves: &[(Vec<bool>, node::Leaf)],
+    ) -> Result<[u8; 32]> {
+        if leaves.is_empty() {
+            return Ok(node_hash);
+        }
+        let (t, il, node_bytes) = self.sto.get(&node_hash)?;
+        match t {
+            constants::TYPENODENORMAL => {
+                let node = node::parse_node_bytes::<H>(node_bytes)?;
+                let split = leaves.partition_point(|l| !l.0[lvl as usize - 1]);
+                let child_l = self.add_leaves(batch, node.child_l, lvl - 1, &leaves[..split])?;
+                let child_r = self.add_leaves(batch, node.child_r, lvl - 1, &leaves[split..])?;
+                let node = node::TreeNode::<H>::with_hasher(child_l, child_r);
+                batch.insert(node.ht(), constants::TYPENODENORMAL, 0, node.bytes());
+                Ok(node.ht())
+            }
+            constants::TYPENODEFINAL | constants::TYPENODEFINALLEAF => {
+                // the leaf already there goes down with the new ones
+                let leaf = node::parse_final_node_bytes::<H>(t, il, node_bytes)?;
+                let path = utils::get_path(self.num_levels, leaf.hi);
+                let pos = leaves.partition_point(|l| l.0.iter().rev().lt(path.iter().rev()));
+                if pos < leaves.len() && leaves[pos].0 == path {
+                    return Err(Error::KeyAlreadyExists);
+                }
+                let mut all = leaves.to_vec();
+                all.insert(pos, (path, leaf));
+                Self::build_subtree(batch, lvl, &all)
+            }
+            _ => Self::build_subtree(batch, lvl, leaves),
+        }
+    }
+
+    // builds the subtree at level lvl holding only the given (sorted) leaves
+    fn build_subtree(
+        batch: &mut db::Batch,
+        lvl: u32,
+        leaves: &[(Vec<bool>, node::Leaf)],
+    ) -> Result<[u8; 32]> {
+        match leaves.len() {
+            0 => Ok(constants::EMPTYNODEVALUE),
+            1 => Ok(Self::final_node(batch, lvl, &leaves[0])),
+            _ => {
+                if lvl == 0 {
+                    return Err(Error::KeyAlreadyExists);
+                }
+                let split = leaves.partition_point(|l| !l.0[lvl as usize - 1]);
+                let child_l = Self::build_subtree(batch, lvl - 1, &leaves[..split])?;
+                let child_r = Self::build_subtree(batch, lvl - 1, &leaves[split..])?;
+                let node = node::TreeNode::<H>::with_hasher(child_l, child_r);
+                batch.insert(node.ht(), constants::TYPENODENORMAL, 0, node.bytes());
+                Ok(node.ht())
+            }
+        }
+    }
+
+    // builds the top of the tree, down to the subtrees already built at
+    // part_lvl (keyed by the position of their first leaf)
+    fn merge_subtrees(
+        batch: &mut db::Batch,
+        lvl: u32,
+        part_lvl: u32,
+        leaves: &[(Vec<bool>, node::Leaf)],
+        offset: usize,
+        subtrees: &HashMap<usize, [u8; 32]>,
+    ) -> Result<[u8; 32]> {
+        match leaves.len() {
+            0 => Ok(constants::EMPTYNODEVALUE),
+            1 => Ok(Self::final_node(batch, lvl, &leaves[0])),
+            _ if lvl == part_lvl => Ok(subtrees[&offset]),
+            _ => {
+                let split = leaves.partition_point(|l| !l.0[lvl as usize - 1]);
+                let child_l = Self::merge_subtrees(
+                    batch,
+                    lvl - 1,
+                    part_lvl,
+                    &leaves[..split],
+                    offset,
+                    subtrees,
+                )?;
+                let child_r = Self::merge_subtrees(
+                    batch,
+                    lvl - 1,
+                    part_lvl,
+                    &leaves[split..],
+                    offset + split,
+                    subtrees,
+                )?;
+                let node = node::TreeNode::<H>::with_hasher(child_l, child_r);
+                batch.insert(node.ht(), constants::TYPENODENORMAL, 0, node.bytes());
+                Ok(node.ht())
+            }
+        }
+    }
+
+    fn final_node(batch: &mut db::Batch, lvl: u32, leaf: &(Vec<bool>, node::Leaf)) -> [u8; 32] {
+        let (path, leaf) = leaf;
+        let final_node_hash = utils::calc_hash_from_leaf_and_level::<H>(lvl, path, leaf.ht);
+        batch.insert(
+            final_node_hash,
+            constants::TYPENODEFINALLEAF,
+            leaf.index_length,
+            leaf.final_node_bytes(),
+        );
+        final_node_hash
+    }
+
+    pub fn update<V: Value>(&mut self, v: &V) -> Result<Vec<u8>> {
+        let path = utils::get_path(self.num_levels, v.hi());
+        let (i, old_leaf, siblings) = self.get_leaf_with_siblings(&path)?;
+        let mut batch = db::Batch::new();
+
+        batch.insert(
             v.ht(),
             constants::TYPENODEVALUE,
             v.index_length(),
             v.bytes().to_vec(),
         );
-        self.sto
-            .insert(self.root_node_key, constants::TYPENODEROOT, 0, self.root.to_vec());
-        return Ok(());
+        let leaf_hash = utils::calc_hash_from_leaf_and_level::<H>(i, &path, v.ht());
+        let path_from_i = utils::cut_path(&path, i as usize);
+        let root = Self::replace_leaf(
+            &mut batch,
+            path_from_i,
+            &siblings,
+            leaf_hash,
+            constants::TYPENODEFINALLEAF,
+            v.index_length(),
+            node::Leaf::from_value(v).final_node_bytes(),
+        );
+        self.commit(batch, root)?;
+        Ok(old_leaf.bytes)
     }
 
-    pub fn replace_leaf(
+    pub fn delete(&mut self, hi: [u8; 32]) -> Result<()> {
+        let path = utils::get_path(self.num_levels, hi);
+        let (i, _, siblings) = self.get_leaf_with_siblings(&path)?;
+        let mut batch = db::Batch::new();
+
+        // go up rebuilding the branch without the leaf. While the branch holds
+        // a single leaf, it is kept as a final node, as it would be if the
+        // deleted leaf had never been added
+        let mut curr_node = constants::EMPTYNODEVALUE;
+        let mut final_leaf: Option<node::Leaf> = None;
+        for (lvl, sibling) in (i..).zip(siblings.iter().rev()) {
+            if *sibling != constants::EMPTYNODEVALUE {
+                if curr_node == constants::EMPTYNODEVALUE {
+                    let (t, il, node_bytes) = self.sto.get(sibling)?;
+                    if node::is_final(t) {
+                        final_leaf = Some(node::parse_final_node_bytes::<H>(t, il, node_bytes)?);
+                    }
+                } else if let Some(leaf) = final_leaf.take() {
+                    // the final node can not go further up
+                    batch.insert(
+                        curr_node,
+                        constants::TYPENODEFINALLEAF,
+                        leaf.index_length,
+                        leaf.final_node_bytes(),
+                    );
+                }
+            }
+            if curr_node == constants::EMPTYNODEVALUE && *sibling == constants::EMPTYNODEVALUE {
+                continue;
+            }
+            if let Some(leaf) = &final_leaf {
+                let path_leaf = utils::get_path(self.num_levels, leaf.hi);
+                curr_node = utils::calc_hash_from_leaf_and_level::<H>(lvl + 1, &path_leaf, leaf.ht);
+                continue;
+            }
+            let node = if !path[lvl as usize] {
+                node::TreeNode::<H>::with_hasher(curr_node, *sibling)
+            } else {
+                node::TreeNode::<H>::with_hasher(*sibling, curr_node)
+            };
+            curr_node = node.ht();
+            batch.insert(curr_node, constants::TYPENODENORMAL, 0, node.bytes());
+        }
+        if let Some(leaf) = final_leaf {
+            batch.insert(
+                curr_node,
+                constants::TYPENODEFINALLEAF,
+                leaf.index_length,
+                leaf.final_node_bytes(),
+            );
+        }
+
+        self.commit(batch, curr_node)?;
+        Ok(())
+    }
+
+    /// Deletes from the storage the nodes that can not be reached from the
+    /// current root nor from `keep_roots`, returning how many were deleted.
+    /// The root entries (the current root and the list of roots) are kept, and
+    /// so are the nodes of all the roots (current and past) of the other trees
+    /// sharing the storage. Records that are not nodes, as the ones of a
+    /// `db::Namespaced` on the same storage, are not touched.
+    pub fn prune(&mut self, keep_roots: &[[u8; 32]]) -> Result<usize> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        for root in keep_roots {
+            let (t, _, _) = self.sto.get(root)?;
+            if *root != constants::EMPTYNODEVALUE
+                && t != constants::TYPENODENORMAL
+                && !node::is_final(t)
+            {
+                return Err(Error::RootNotFound);
+            }
+        }
+
+        // the roots of the other trees sharing the storage (see `with_id`),
+        // current and past, are kept too, only the list of roots of this tree
+        // is left out
+        let mut own_entries = HashSet::new();
+        for seq in 0..self.num_roots()? {
+            own_entries.insert(self.root_entry_key(seq));
+        }
+        let mut pending = keep_roots.to_vec();
+        pending.push(self.root);
+        let mut keys = Vec::new();
+        for k in self.sto.keys()? {
+            let v = match self.sto.get_raw(&k)? {
+                Some(v) => v,
+                None => continue,
+            };
+            // the records that are not nodes (as the ones of a
+            // `db::Namespaced`) are not touched
+            let (t, _, b) = match db::decode_value(&v) {
+                Ok(r) => r,
+                Err(_) => continue,
+            };
+            match t {
+                constants::TYPENODEROOT
+                    if !own_entries.contains(&k) && (b.len() == 32 || b.len() == 40) =>
+                {
+                    pending.push(*array_ref!(b, 0, 32));
+                }
+                constants::TYPENODENORMAL | constants::TYPENODEVALUE => keys.push(k),
+                t if node::is_final(t) => keys.push(k),
+                _ => {}
+            }
+        }
+
+        // mark
+        let mut reachable: HashSet<[u8; 32]> = HashSet::new();
+        while let Some(k) = pending.pop() {
+            if k == constants::EMPTYNODEVALUE || !reachable.insert(k) {
+                continue;
+            }
+            let (t, il, node_bytes) = self.sto.get(&k)?;
+            if t == constants::TYPENODENORMAL {
+                let node = node::parse_node_bytes::<H>(node_bytes)?;
+                pending.push(node.child_l);
+                pending.push(node.child_r);
+            } else if node::is_final(t) {
+                // the value of the leaf is stored by its ht
+                let leaf = node::parse_final_node_bytes::<H>(t, il, node_bytes)?;
+                reachable.insert(leaf.ht);
+            }
+        }
+
+        // sweep
+        let mut batch = db::Batch::new();
+        for k in keys {
+            if !reachable.contains(&k) {
+                batch.delete(k);
+            }
+        }
+        let deleted = batch.len();
+        self.sto.batch(batch)?;
+        Ok(deleted)
+    }
+
+    /// Walks the nodes reachable from the root, counting them, and the whole
+    /// storage for its size. A node missing from the storage gives
+    /// `Error::CorruptNode`.
+    pub fn stats(&mut self) -> Result<TreeStats> {
+        let mut stats = TreeStats::default();
+        let mut final_depths = 0;
+        let mut pending = vec![(self.root, 0)];
+        while let Some((k, depth)) = pending.pop() {
+            if k == constants::EMPTYNODEVALUE {
+                continue;
+            }
+            let (t, il, node_bytes) = self.sto.get(&k)?;
+            match t {
+                constants::TYPENODENORMAL => {
+                    stats.normal_nodes += 1;
+                    let node = node::parse_node_bytes::<H>(node_bytes)?;
+                    pending.push((node.child_l, depth + 1));
+                    pending.push((node.child_r, depth + 1));
+                }
+                constants::TYPENODEFINAL | constants::TYPENODEFINALLEAF => {
+                    stats.leaves += 1;
+                    stats.final_nodes += 1;
+                    stats.max_final_depth = stats.max_final_depth.max(depth);
+                    final_depths += depth as u64;
+                    // with compact leaves the final node is the one at ht
+                    let leaf = node::parse_final_node_bytes::<H>(t, il, node_bytes)?;
+                    if self.sto.get(&leaf.ht)?.0 == constants::TYPENODEVALUE {
+                        stats.value_nodes += 1;
+                    }
+                }
+                constants::TYPENODEVALUE => {
+                    stats.leaves += 1;
+                    stats.value_nodes += 1;
+                }
+                // a node missing from the storage, the tree is not smaller
+                // but damaged
+                _ => return Err(Error::CorruptNode),
+            }
+        }
+        if stats.final_nodes > 0 {
+            stats.avg_final_depth = final_depths as f64 / stats.final_nodes as f64;
+        }
+        for k in self.sto.keys()? {
+            stats.store_keys += 1;
+            if let Some(v) = self.sto.get_raw(&k)? {
+                stats.store_bytes += v.len();
+            }
+        }
+        Ok(stats)
+    }
+
+    // goes down the path until the leaf placed in it, returning the level of
+    // the leaf, the leaf and the siblings from the root to the leaf
+    fn get_leaf_with_siblings(
         &mut self,
+        path: &[bool],
+    ) -> Result<(u32, node::Leaf, Vec<[u8; 32]>)> {
+        let mut siblings: Vec<[u8; 32]> = Vec::new();
+        let mut node_hash = self.root;
+
+        // i is the level of node_hash
+        let mut i = self.num_levels - 1;
+        loop {
+            let (t, il, node_bytes) = self.sto.get(&node_hash)?;
+            if node::is_final(t) {
+                let leaf = node::parse_final_node_bytes::<H>(t, il, node_bytes)?;
+                let path_node = utils::get_path(self.num_levels, leaf.hi);
+                if utils::compare_paths(&path_node, path) != -1 {
+                    return Err(Error::KeyNotFound);
+                }
+                return Ok((i, leaf, siblings));
+            }
+            if t != constants::TYPENODENORMAL || i == 0 {
+                return Err(Error::KeyNotFound);
+            }
+            i -= 1;
+            let node = node::parse_node_bytes::<H>(node_bytes)?;
+            if !path[i as usize] {
+                node_hash = node.child_l;
+                siblings.push(node.child_r);
+            } else {
+                node_hash = node.child_r;
+                siblings.push(node.child_l);
+            }
+        }
+    }
+
+    // writes the whole mutation and the new root at once, so the stored tree
+    // is never left with a root pointing to nodes that were not written
+    fn commit(&mut self, mut batch: db::Batch, root: [u8; 32]) -> Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        batch.insert(
+            self.root_node_key,
+            constants::TYPENODEROOT,
+            0,
+            root.to_vec(),
+        );
+        if self.record_roots {
+            let seq = self.num_roots()?;
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            let mut entry = root.to_vec();
+            entry.extend(timestamp.to_le_bytes().iter());
+            batch.insert(self.root_entry_key(seq), constants::TYPENODEROOT, 0, entry);
+            batch.insert(
+                self.roots_key,
+                constants::TYPENODEROOT,
+                0,
+                (seq + 1).to_le_bytes().to_vec(),
+            );
+        }
+        self.sto.batch(batch)?;
+        self.root = root;
+        Ok(())
+    }
+
+    fn root_entry_key(&self, seq: u64) -> [u8; 32] {
+        let mut b = self.roots_key.to_vec();
+        b.extend(seq.to_le_bytes().iter());
+        utils::hash_vec(b)
+    }
+
+    fn num_roots(&mut self) -> Result<u64> {
+        let (t, _, b) = self.sto.get(&self.roots_key)?;
+        if t == constants::TYPENODEEMPTY {
+            return Ok(0);
+        }
+        if b.len() != 8 {
+            return Err(Error::CorruptNode);
+        }
+        Ok(u64::from_le_bytes(*array_ref!(b, 0, 8)))
+    }
+
+    /// Roots committed to the storage by each change to the tree (each
+    /// transaction counts as one), from the oldest to the newest.
+    pub fn roots(&mut self) -> Result<Vec<RootEntry>> {
+        let mut roots = Vec::new();
+        for seq in 0..self.num_roots()? {
+            let (_, _, b) = self.sto.get(&self.root_entry_key(seq))?;
+            if b.len() != 40 {
+                return Err(Error::CorruptNode);
+            }
+            roots.push(RootEntry {
+                seq,
+                root: *array_ref!(b, 0, 32),
+                timestamp: u64::from_le_bytes(*array_ref!(b, 32, 8)),
+            });
+        }
+        Ok(roots)
+    }
+
+    pub fn replace_leaf(
+        batch: &mut db::Batch,
         path: Vec<bool>,
-        siblings: &Vec<[u8; 32]>,
+        siblings: &[[u8; 32]],
         leaf_hash: [u8; 32],
         node_type: u8,
         index_length: u32,
         leaf_value: Vec<u8>,
     ) -> [u8; 32] {
-        self.sto
-            .insert(leaf_hash, node_type, index_length, leaf_value);
+        batch.insert(leaf_hash, node_type, index_length, leaf_value);
         let mut curr_node = leaf_hash;
 
         for i in 0..siblings.len() {
-            if !path[i as usize] {
-                let node = node::TreeNode {
-                    child_l: curr_node,
-                    child_r: siblings[siblings.len() - 1 - i],
-                };
-                self.sto
-                    .insert(node.ht(), constants::TYPENODENORMAL, 0, node.bytes());
+            if !path[i] {
+                let node =
+                    node::TreeNode::<H>::with_hasher(curr_node, siblings[siblings.len() - 1 - i]);
+                batch.insert(node.ht(), constants::TYPENODENORMAL, 0, node.bytes());
                 curr_node = node.ht();
             } else {
-                let node = node::TreeNode {
-                    child_l: siblings[siblings.len() - 1 - i],
-                    child_r: curr_node,
-                };
-                self.sto
-                    .insert(node.ht(), constants::TYPENODENORMAL, 0, node.bytes());
+                let node =
+                    node::TreeNode::<H>::with_hasher(siblings[siblings.len() - 1 - i], curr_node);
+                batch.insert(node.ht(), constants::TYPENODENORMAL, 0, node.bytes());
                 curr_node = node.ht();
             }
         }
         curr_node
     }
 
-    pub fn get_value_in_pos(&mut self, hi: [u8; 32]) -> Vec<u8> {
+    pub fn get_value_in_pos(&mut self, hi: [u8; 32]) -> Result<Vec<u8>> {
         let path = utils::get_path(self.num_levels, hi);
         let mut node_hash = self.root;
         for i in (0..=self.num_levels - 2).rev() {
-            let (t, il, node_bytes) = self.sto.get(&node_hash);
-            if t == constants::TYPENODEFINAL {
-                let hi_node = utils::hash_vec(node_bytes.to_vec().split_at(il as usize).0.to_vec());
-                let path_node = utils::get_path(self.num_levels, hi_node);
+            let (t, il, node_bytes) = self.sto.get(&node_hash)?;
+            if node::is_final(t) {
+                let leaf = node::parse_final_node_bytes::<H>(t, il, node_bytes)?;
+                let path_node = utils::get_path(self.num_levels, leaf.hi);
                 let pos_diff = utils::compare_paths(&path_node, &path);
                 if pos_diff != -1 {
-                    return constants::EMPTYNODEVALUE.to_vec();
+                    return Ok(constants::EMPTYNODEVALUE.to_vec());
                 }
-                return node_bytes;
+                return Ok(leaf.bytes);
             }
-            let node = node::parse_node_bytes(node_bytes);
+            let node = node::parse_node_bytes::<H>(node_bytes)?;
             if !path[i as usize] {
                 node_hash = node.child_l;
             } else {
                 node_hash = node.child_r;
             }
         }
-        let (_t, _il, node_bytes) = self.sto.get(&node_hash);
-        node_bytes
+        let (t, il, node_bytes) = self.sto.get(&node_hash)?;
+        if node::is_final(t) {
+            return Ok(node::parse_final_node_bytes::<H>(t, il, node_bytes)?.bytes);
+        }
+        Ok(node_bytes)
+    }
+
+    /// Iterates over the leaves of the tree in path order, yielding their
+    /// (hi, index_length, bytes).
+    pub fn iter(&mut self) -> Leaves<'_, S, H> {
+        Leaves::new(&mut *self.sto, self.num_levels, self.root)
+    }
+
+    /// Iterates, in path order, over the leaves under the subtree at the end
+    /// of `path_prefix`. The prefix has the order of `utils::get_path`, its last
+    /// bit is the one used at the root, so the leaves with a given path are
+    /// the ones under any of its tails `&path[i..]`.
+    pub fn iter_prefix(&mut self, path_prefix: &[bool]) -> Leaves<'_, S, H> {
+        Leaves::with_prefix(&mut *self.sto, self.num_levels, self.root, path_prefix)
+    }
+
+    pub fn generate_proof(&mut self, hi: [u8; 32]) -> Result<MerkleProof> {
+        self.generate_proof_with_aux(hi, false)
+    }
+
+    /// Generates a single proof of all the leaves with the given hi, sharing
+    /// the siblings common to their paths.
+    pub fn generate_multi_proof(&mut self, his: &[[u8; 32]]) -> Result<MultiProof> {
+        let mut leaves: Vec<(Vec<bool>, [u8; 32])> = his
+            .iter()
+            .map(|hi| (utils::get_path(self.num_levels, *hi), *hi))
+            .collect();
+        leaves.sort_by(|a, b| a.0.iter().rev().cmp(b.0.iter().rev()));
+        leaves.dedup();
+        if leaves.is_empty() {
+            return Err(Error::KeyNotFound);
+        }
+        let mut mp = MultiProof {
+            bits: Vec::new(),
+            siblings: Vec::new(),
+        };
+        let root = self.root;
+        let lvl = self.num_levels - 1;
+        self.multi_proof_node(&mut mp, root, lvl, &leaves)?;
+        Ok(mp)
+    }
+
+    // walks the subtree of node_hash, at level lvl, down to the given
+    // (sorted) leaves, in the same order as proof::verify_multi_proof
+    fn multi_proof_node(
+        &mut self,
+        mp: &mut MultiProof,
+        node_hash: [u8; 32],
+        lvl: u32,
+        leaves: &[(Vec<bool>, [u8; 32])],
+    ) -> Result<()> {
+        let (t, il, node_bytes) = self.sto.get(&node_hash)?;
+        if leaves.len() == 1 {
+            if node::is_final(t) {
+                let leaf = node::parse_final_node_bytes::<H>(t, il, node_bytes)?;
+                if leaf.hi != leaves[0].1 {
+                    return Err(Error::KeyNotFound);
+                }
+                mp.bits.push(true);
+                return Ok(());
+            }
+            mp.bits.push(false);
+        }
+        if t != constants::TYPENODENORMAL || lvl == 0 {
+            return Err(Error::KeyNotFound);
+        }
+        let node = node::parse_node_bytes::<H>(node_bytes)?;
+        let split = leaves.partition_point(|l| !l.0[lvl as usize - 1]);
+        for (child, side) in [
+            (node.child_l, &leaves[..split]),
+            (node.child_r, &leaves[split..]),
+        ] {
+            if side.is_empty() {
+                mp.bits.push(child != constants::EMPTYNODEVALUE);
+                if child != constants::EMPTYNODEVALUE {
+                    mp.siblings.push(child);
+                }
+            } else {
+                self.multi_proof_node(mp, child, lvl - 1, side)?;
+            }
+        }
+        Ok(())
     }
 
-    pub fn generate_proof(&mut self, hi: [u8; 32]) -> Vec<u8> {
-        let mut mp: Vec<u8> = Vec::new();
+    /// Generates a proof of non existence of hi, which carries the leaf found
+    /// in the path of hi (if any) instead of folding it into the siblings.
+    pub fn generate_non_existence_proof(&mut self, hi: [u8; 32]) -> Result<MerkleProof> {
+        let mp = self.generate_proof_with_aux(hi, true)?;
+        if mp.existence {
+            return Err(Error::KeyAlreadyExists);
+        }
+        Ok(mp)
+    }
 
-        let mut empties: [u8; 32] = [0; 32];
+    fn generate_proof_with_aux(&mut self, hi: [u8; 32], with_aux: bool) -> Result<MerkleProof> {
+        let mut mp = MerkleProof {
+            existence: true,
+            empties: [0; 32],
+            siblings: Vec::new(),
+            aux: None,
+        };
         let path = utils::get_path(self.num_levels, hi);
 
-        let mut siblings: Vec<[u8; 32]> = Vec::new();
         let mut node_hash = self.root;
 
         for i in 0..self.num_levels {
-            let (t, il, node_bytes) = self.sto.get(&node_hash);
-            if t == constants::TYPENODEFINAL {
-                let real_value_in_pos = self.get_value_in_pos(hi);
-                if real_value_in_pos == constants::EMPTYNODEVALUE {
-                    let leaf_hi =
-                        utils::hash_vec(node_bytes.to_vec().split_at(il as usize).0.to_vec());
-                    let path_child = utils::get_path(self.num_levels, leaf_hi);
-                    let pos_diff = utils::compare_paths(&path_child, &path);
-                    if pos_diff as u32 == self.num_levels {
-                        return mp;
-                    }
-                    if pos_diff as u32 != self.num_levels - 1 - i {
-                        let sibling = utils::calc_hash_from_leaf_and_level(
+            let (t, il, node_bytes) = self.sto.get(&node_hash)?;
+            if t == constants::TYPENODEEMPTY {
+                mp.existence = false;
+                break;
+            }
+            if node::is_final(t) {
+                let leaf = node::parse_final_node_bytes::<H>(t, il, node_bytes)?;
+                let path_child = utils::get_path(self.num_levels, leaf.hi);
+                let pos_diff = utils::compare_paths(&path_child, &path);
+                if pos_diff != -1 {
+                    mp.existence = false;
+                    // with compact leaves, the leaf found can only be shown
+                    // to be a different one through the aux
+                    if with_aux || H::COMPACT_LEAVES {
+                        mp.aux = Some(proof::NodeAux {
+                            hi: leaf.hi,
+                            ht: leaf.ht,
+                        });
+                    } else if pos_diff as u32 != self.num_levels - 1 - i {
+                        let sibling = utils::calc_hash_from_leaf_and_level::<H>(
                             pos_diff as u32,
                             &path_child,
-                            utils::hash_vec(node_bytes.to_vec()),
+                            leaf.ht,
                         );
-                        let mut new_siblings: Vec<[u8; 32]> = Vec::new();
-                        new_siblings.push(sibling);
-                        new_siblings.extend(siblings);
-                        siblings = new_siblings;
-                        // set empties bit
-                        let bit_pos = self.num_levels - 2 - pos_diff as u32;
-                        empties[(empties.len() as isize + (bit_pos as isize / 8 - 1) as isize)
-                            as usize] |= 1 << (bit_pos % 8);
+                        mp.siblings.insert(0, sibling);
+                        mp.set_non_empty_sibling(self.num_levels - 2 - pos_diff as u32);
                     }
                 }
                 break;
             }
-            let node = node::parse_node_bytes(node_bytes);
+            let node = node::parse_node_bytes::<H>(node_bytes)?;
             let sibling: [u8; 32];
             if !path[self.num_levels as usize - i as usize - 2] {
                 node_hash = node.child_l;
@@ -315,134 +1126,96 @@ impl<'a> MerkleTree<'a> {
                 node_hash = node.child_r;
             }
             if sibling != constants::EMPTYNODEVALUE {
-                // set empties bit
-                empties[(empties.len() as isize + (i as isize / 8 - 1) as isize) as usize] |=
-                    1 << (i % 8);
-                let mut new_siblings: Vec<[u8; 32]> = Vec::new();
-                new_siblings.push(sibling);
-                new_siblings.extend(siblings);
-                siblings = new_siblings;
+                mp.siblings.insert(0, sibling);
+                mp.set_non_empty_sibling(i);
             }
         }
-        mp.append(&mut empties[..].to_vec());
-        for s in siblings {
-            mp.append(&mut s.to_vec());
-        }
-        mp
+        Ok(mp)
     }
 
-    pub fn print_level(&mut self, parent: [u8; 32], mut lvl: u32, max_level: u32) {
+    pub fn print_level(&mut self, parent: [u8; 32], mut lvl: u32, max_level: u32) -> Result<()> {
         let mut line: String = "".to_string();
         for _ in 0..lvl {
-            line += &format!(" ");
+            line += " ";
         }
         line += &format!("lvl {}", lvl);
         line += &format!(" - '{}'  = ", parent.to_hex());
-        let (t, _, node_bytes) = self.sto.get(&parent);
-        let mut node = node::TreeNode {
-            child_l: constants::EMPTYNODEVALUE,
-            child_r: constants::EMPTYNODEVALUE,
-        };
+        let (t, il, node_bytes) = self.sto.get(&parent)?;
+        let mut node =
+            node::TreeNode::<H>::with_hasher(constants::EMPTYNODEVALUE, constants::EMPTYNODEVALUE);
         if t == constants::TYPENODENORMAL {
-            node = node::parse_node_bytes(node_bytes);
+            node = node::parse_node_bytes::<H>(node_bytes)?;
             line += &format!("'{}' - '{}'", node.child_l.to_hex(), node.child_r.to_hex());
         } else if t == constants::TYPENODEVALUE {
             //
-        } else if t == constants::TYPENODEFINAL {
-            let hash_node_bytes = utils::hash_vec(node_bytes);
-            line += &format!("[final] final tree node: {} \n", hash_node_bytes.to_hex());
-            let (_, _, leaf_node_bytes) = self.sto.get(&hash_node_bytes);
+        } else if node::is_final(t) {
+            let leaf = node::parse_final_node_bytes::<H>(t, il, node_bytes)?;
+            line += &format!("[final] final tree node: {} \n", leaf.ht.to_hex());
             for _ in 0..lvl {
                 line += "  ";
             }
-            let leaf_node_string = String::from_utf8_lossy(&leaf_node_bytes);
+            let leaf_node_string = String::from_utf8_lossy(&leaf.bytes);
             line += &format!("leaf value: {}", leaf_node_string);
         } else {
             line += "[EMPTY Branch]"
         }
         println!("{}", line);
         lvl += 1;
-        if node.child_r.len() > 0
+        if !node.child_r.is_empty()
             && lvl < max_level
             && t != constants::TYPENODEEMPTY
-            && t != constants::TYPENODEFINAL
+            && !node::is_final(t)
         {
-            self.print_level(node.child_l, lvl, max_level);
-            self.print_level(node.child_r, lvl, max_level);
+            self.print_level(node.child_l, lvl, max_level)?;
+            self.print_level(node.child_r, lvl, max_level)?;
         }
+        Ok(())
     }
-    pub fn print_full_tree(&mut self) {
-        let root = self.root.clone();
-        let num_levels = self.num_levels.clone();
-        self.print_level(root, 0, num_levels - 1);
+    pub fn print_full_tree(&mut self) -> Result<()> {
+        let root = self.root;
+        let num_levels = self.num_levels;
+        self.print_level(root, 0, num_levels - 1)?;
         println!("root {:?}", &self.root.to_hex());
+        Ok(())
     }
-    pub fn print_levels_tree(&mut self, max_level: u32) {
-        let root = self.root.clone();
-        let num_levels = self.num_levels.clone();
-        self.print_level(root, 0, num_levels - 1 - max_level);
+    pub fn print_levels_tree(&mut self, max_level: u32) -> Result<()> {
+        let root = self.root;
+        let num_levels = self.num_levels;
+        self.print_level(root, 0, num_levels - 1 - max_level)?;
         println!("root {:?}", self.root.to_hex());
+        Ok(())
     }
 }
 
-pub fn verify_proof(
-    root: [u8; 32],
-    mp: &Vec<u8>,
-    hi: [u8; 32],
-    ht: [u8; 32],
-    num_levels: u32,
-) -> bool {
-    let empties: Vec<u8>;
-    empties = mp.split_at(32).0.to_vec();
-
-    let mut siblings: Vec<[u8; 32]> = Vec::new();
-    for i in (empties.len()..mp.len()).step_by(constants::EMPTYNODEVALUE.len()) {
-        let mut sibling: [u8; 32] = [0; 32];
-        sibling.copy_from_slice(&mp[i..i + constants::EMPTYNODEVALUE.len()]);
-        siblings.push(sibling);
-    }
-
-    let path = utils::get_path(num_levels, hi);
-    let mut node_hash = ht;
-    let mut sibling_used_pos = 0;
-
-    for i in (0..=num_levels - 2).rev() {
-        let sibling: [u8; 32];
-        if (empties[empties.len() - i as usize / 8 - 1] & (1 << (i % 8))) > 0 {
-            sibling = siblings[sibling_used_pos];
-            sibling_used_pos += 1;
-        } else {
-            sibling = constants::EMPTYNODEVALUE;
-        }
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use db::Storage;
+    use rustc_hex::ToHex;
 
-        let n: node::TreeNode;
-        if path[num_levels as usize - i as usize - 2] {
-            n = node::TreeNode {
-                child_l: sibling,
-                child_r: node_hash,
-            }
-        } else {
-            n = node::TreeNode {
-                child_l: node_hash,
-                child_r: sibling,
-            }
-        }
-        if node_hash == constants::EMPTYNODEVALUE && sibling == constants::EMPTYNODEVALUE {
-            node_hash = constants::EMPTYNODEVALUE;
-        } else {
-            node_hash = n.ht();
-        }
+    fn storages() -> Vec<Box<dyn Storage>> {
+        vec![
+            Box::new(db::Db::new("test".to_string(), true).unwrap()),
+            Box::new(db::MemoryDb::new()),
+        ]
     }
-    if node_hash == root {
-        return true;
+
+    // value i of the tests, hashed with H
+    pub(crate) fn val_with_hasher<H: Hasher>(i: u32) -> TestValue<H> {
+        TestValue::with_hasher(
+            (i.to_string() + " this is a test leaf").as_bytes().to_vec(),
+            15,
+        )
     }
-    false
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use rustc_hex::ToHex;
+    pub(crate) fn val(i: u32) -> TestValue {
+        val_with_hasher(i)
+    }
+
+    // the values 0..n of the tests
+    pub(crate) fn vals(n: u32) -> Vec<TestValue> {
+        (0..n).map(val).collect()
+    }
 
     #[test]
     fn test_hash_vec() {
@@ -456,23 +1229,52 @@ mod tests {
 
     #[test]
     fn test_new_mt() {
-        let mut sto = db::Db::new("test".to_string(), true);
-        let mt = MerkleTree::new(&mut sto, 140);
-        assert_eq!(140, mt.num_levels);
-        assert_eq!(
-            "0000000000000000000000000000000000000000000000000000000000000000",
-            mt.root.to_hex()
-        );
-        let (_t, _il, b) = mt.sto.get(&[0; 32]);
-        assert_eq!(mt.root.to_vec(), b);
+        for mut sto in storages() {
+            let mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            assert_eq!(140, mt.num_levels);
+            assert_eq!(
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                mt.root.to_hex()
+            );
+            let (_t, _il, b) = mt.sto.get(&[0; 32]).unwrap();
+            assert_eq!(mt.root.to_vec(), b);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        for mut sto in storages() {
+            assert!(MerkleTree::new(&mut *sto, 1).is_err());
+            assert_eq!(
+                Error::InvalidNumLevels(258),
+                MerkleTree::new(&mut *sto, 258).err().unwrap()
+            );
+            let mut mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            let val = TestValue::new("this is a test leaf".as_bytes().to_vec(), 15);
+            mt.add(&val).unwrap();
+            assert_eq!(Err(Error::KeyAlreadyExists), mt.add(&val));
+            assert_eq!(Err(Error::KeyNotFound), mt.delete([1; 32]));
+
+            // a root entry of the wrong type or length
+            let rnk = utils::hash_vec("root".as_bytes().to_vec());
+            sto.insert(rnk, constants::TYPENODEROOT, 0, vec![1; 8])
+                .unwrap();
+            assert_eq!(
+                Error::CorruptNode,
+                MerkleTree::new(&mut *sto, 140).err().unwrap()
+            );
+            sto.insert(rnk, constants::TYPENODEFINAL, 0, vec![1; 32])
+                .unwrap();
+            assert_eq!(
+                Error::CorruptNode,
+                MerkleTree::new(&mut *sto, 140).err().unwrap()
+            );
+        }
     }
 
     #[test]
     fn test_tree_node() {
-        let n = node::TreeNode {
-            child_l: [1; 32],
-            child_r: [2; 32],
-        };
+        let n = node::TreeNode::new([1; 32], [2; 32]);
         assert_eq!("01010101010101010101010101010101010101010101010101010101010101010202020202020202020202020202020202020202020202020202020202020202",
                     n.bytes().to_hex());
         assert_eq!(
@@ -483,132 +1285,182 @@ mod tests {
 
     #[test]
     fn test_add() {
-        let mut sto = db::Db::new("test".to_string(), true);
-        let mut mt = MerkleTree::new(&mut sto, 140);
-        assert_eq!(
-            "0000000000000000000000000000000000000000000000000000000000000000",
-            mt.root.to_hex()
-        );
-        let val = TestValue {
-            bytes: vec![1, 2, 3, 4, 5],
-            index_length: 3,
-        };
-        mt.add(&val).unwrap();
-        let (_t, _il, b) = mt.sto.get(&val.ht());
-        assert_eq!(*val.bytes(), b);
-        assert_eq!(
-            "a0e72cc948119fcb71b413cf5ada12b2b825d5133299b20a6d9325ffc3e2fbf1",
-            mt.root.to_hex()
-        );
+        for mut sto in storages() {
+            let mut mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            assert_eq!(
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                mt.root.to_hex()
+            );
+            let val = TestValue::new(vec![1, 2, 3, 4, 5], 3);
+            mt.add(&val).unwrap();
+            let (_t, _il, b) = mt.sto.get(&val.ht()).unwrap();
+            assert_eq!(*val.bytes(), b);
+            assert_eq!(
+                "a0e72cc948119fcb71b413cf5ada12b2b825d5133299b20a6d9325ffc3e2fbf1",
+                mt.root.to_hex()
+            );
+        }
     }
 
     #[test]
     fn test_add_2() {
-        let mut sto = db::Db::new("test".to_string(), true);
-        let mut mt = MerkleTree::new(&mut sto, 140);
-        let val = TestValue {
-            bytes: "this is a test leaf".as_bytes().to_vec(),
-            index_length: 15,
-        };
-        assert_eq!(
-            "0000000000000000000000000000000000000000000000000000000000000000",
-            mt.root.to_hex()
-        );
-        mt.add(&val).unwrap();
-        let (_t, _il, b) = mt.sto.get(&val.ht());
-        assert_eq!(*val.bytes(), b);
-        assert_eq!(
-            "b4fdf8a653198f0e179ccb3af7e4fc09d76247f479d6cfc95cd92d6fda589f27",
-            mt.root.to_hex()
-        );
-        let val2 = TestValue {
-            bytes: "this is a second test leaf".as_bytes().to_vec(),
-            index_length: 15,
-        };
-        mt.add(&val2).unwrap();
-        let (_t, _il, b) = mt.sto.get(&val2.ht());
-        assert_eq!(*val2.bytes(), b);
-        assert_eq!(
-            "8ac95e9c8a6fbd40bb21de7895ee35f9c8f30ca029dbb0972c02344f49462e82",
-            mt.root.to_hex()
-        );
+        for mut sto in storages() {
+            let mut mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            let val = TestValue::new("this is a test leaf".as_bytes().to_vec(), 15);
+            assert_eq!(
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                mt.root.to_hex()
+            );
+            mt.add(&val).unwrap();
+            let (_t, _il, b) = mt.sto.get(&val.ht()).unwrap();
+            assert_eq!(*val.bytes(), b);
+            assert_eq!(
+                "b4fdf8a653198f0e179ccb3af7e4fc09d76247f479d6cfc95cd92d6fda589f27",
+                mt.root.to_hex()
+            );
+            let val2 = TestValue::new("this is a second test leaf".as_bytes().to_vec(), 15);
+            mt.add(&val2).unwrap();
+            let (_t, _il, b) = mt.sto.get(&val2.ht()).unwrap();
+            assert_eq!(*val2.bytes(), b);
+            assert_eq!(
+                "8ac95e9c8a6fbd40bb21de7895ee35f9c8f30ca029dbb0972c02344f49462e82",
+                mt.root.to_hex()
+            );
+        }
     }
 
     #[test]
     fn test_generate_proof_and_verify_proof() {
-        let mut sto = db::Db::new("test".to_string(), true);
-        let mut mt = MerkleTree::new(&mut sto, 140);
-        let val = TestValue {
-            bytes: "this is a test leaf".as_bytes().to_vec(),
-            index_length: 15,
-        };
-        assert_eq!(
-            "0000000000000000000000000000000000000000000000000000000000000000",
-            mt.root.to_hex()
-        );
-        mt.add(&val).unwrap();
-        let (_t, _il, b) = mt.sto.get(&val.ht());
-        assert_eq!(*val.bytes(), b);
-        assert_eq!(
-            "b4fdf8a653198f0e179ccb3af7e4fc09d76247f479d6cfc95cd92d6fda589f27",
-            mt.root.to_hex()
-        );
-        let val2 = TestValue {
-            bytes: "this is a second test leaf".as_bytes().to_vec(),
-            index_length: 15,
-        };
-        mt.add(&val2).unwrap();
-        let (_t, _il, b) = mt.sto.get(&val2.ht());
-        assert_eq!(*val2.bytes(), b);
-        assert_eq!(
-            "8ac95e9c8a6fbd40bb21de7895ee35f9c8f30ca029dbb0972c02344f49462e82",
-            mt.root.to_hex()
-        );
+        for mut sto in storages() {
+            let mut mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            let val = TestValue::new("this is a test leaf".as_bytes().to_vec(), 15);
+            assert_eq!(
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                mt.root.to_hex()
+            );
+            mt.add(&val).unwrap();
+            let (_t, _il, b) = mt.sto.get(&val.ht()).unwrap();
+            assert_eq!(*val.bytes(), b);
+            assert_eq!(
+                "b4fdf8a653198f0e179ccb3af7e4fc09d76247f479d6cfc95cd92d6fda589f27",
+                mt.root.to_hex()
+            );
+            let val2 = TestValue::new("this is a second test leaf".as_bytes().to_vec(), 15);
+            mt.add(&val2).unwrap();
+            let (_t, _il, b) = mt.sto.get(&val2.ht()).unwrap();
+            assert_eq!(*val2.bytes(), b);
+            assert_eq!(
+                "8ac95e9c8a6fbd40bb21de7895ee35f9c8f30ca029dbb0972c02344f49462e82",
+                mt.root.to_hex()
+            );
 
-        let mp = mt.generate_proof(val2.hi());
-        assert_eq!("0000000000000000000000000000000000000000000000000000000000000001fd8e1a60cdb23c0c7b2cf8462c99fafd905054dccb0ed75e7c8a7d6806749b6b", mp.to_hex());
+            let mp = mt.generate_proof(val2.hi()).unwrap();
+            assert_eq!("0000000000000000000000000000000000000000000000000000000000000001fd8e1a60cdb23c0c7b2cf8462c99fafd905054dccb0ed75e7c8a7d6806749b6b", mp.to_bytes().to_hex());
 
-        // verify
-        let v = verify_proof(mt.root, &mp, val2.hi(), val2.ht(), mt.num_levels);
-        assert_eq!(true, v);
+            // verify
+            let v = verify_proof(mt.root, &mp, val2.hi(), val2.ht(), mt.num_levels);
+            assert!(v);
+        }
     }
 
     #[test]
     fn test_generate_proof_empty_leaf_and_verify_proof() {
-        let mut sto = db::Db::new("test".to_string(), true);
-        let mut mt = MerkleTree::new(&mut sto, 140);
-        let val = TestValue {
-            bytes: "this is a test leaf".as_bytes().to_vec(),
-            index_length: 15,
-        };
-        mt.add(&val).unwrap();
-        let val2 = TestValue {
-            bytes: "this is a second test leaf".as_bytes().to_vec(),
-            index_length: 15,
-        };
-        mt.add(&val2).unwrap();
-        assert_eq!(
-            "8ac95e9c8a6fbd40bb21de7895ee35f9c8f30ca029dbb0972c02344f49462e82",
-            mt.root.to_hex()
-        );
+        for mut sto in storages() {
+            let mut mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            let val = TestValue::new("this is a test leaf".as_bytes().to_vec(), 15);
+            mt.add(&val).unwrap();
+            let val2 = TestValue::new("this is a second test leaf".as_bytes().to_vec(), 15);
+            mt.add(&val2).unwrap();
+            assert_eq!(
+                "8ac95e9c8a6fbd40bb21de7895ee35f9c8f30ca029dbb0972c02344f49462e82",
+                mt.root.to_hex()
+            );
 
-        // proof of empty leaf
-        let val3 = TestValue {
-            bytes: "this is a third test leaf".as_bytes().to_vec(),
-            index_length: 15,
-        };
-        let mp = mt.generate_proof(val3.hi());
-        assert_eq!("000000000000000000000000000000000000000000000000000000000000000389741fa23da77c259781ad8f4331a5a7d793eef1db7e5200ddfc8e5f5ca7ce2bfd8e1a60cdb23c0c7b2cf8462c99fafd905054dccb0ed75e7c8a7d6806749b6b", mp.to_hex());
+            // proof of empty leaf
+            let val3 = TestValue::new("this is a third test leaf".as_bytes().to_vec(), 15);
+            let mp = mt.generate_proof(val3.hi()).unwrap();
+            assert_eq!("000000000000000000000000000000000000000000000000000000000000000389741fa23da77c259781ad8f4331a5a7d793eef1db7e5200ddfc8e5f5ca7ce2bfd8e1a60cdb23c0c7b2cf8462c99fafd905054dccb0ed75e7c8a7d6806749b6b", mp.to_bytes().to_hex());
 
-        // verify that is a proof of an empty leaf (constants::EMPTYNODEVALUE)
-        let v = verify_proof(
-            mt.root,
-            &mp,
-            val3.hi(),
-            constants::EMPTYNODEVALUE,
-            mt.num_levels,
-        );
-        assert_eq!(true, v);
+            // verify that is a proof of an empty leaf (constants::EMPTYNODEVALUE)
+            let v = verify_proof(
+                mt.root,
+                &mp,
+                val3.hi(),
+                constants::EMPTYNODEVALUE,
+                mt.num_levels,
+            );
+            assert!(v);
+        }
+    }
+
+    #[test]
+    fn test_generate_proof_empty_tree() {
+        for mut sto in storages() {
+            let mut mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            let mp = mt.generate_proof([1; 32]).unwrap();
+            assert!(!mp.existence);
+            assert!(mp.siblings.is_empty());
+            assert!(verify_proof(
+                mt.root,
+                &mp,
+                [1; 32],
+                constants::EMPTYNODEVALUE,
+                mt.num_levels
+            ));
+            assert!(!verify_proof(mt.root, &mp, [1; 32], [2; 32], mt.num_levels));
+
+            // when the path ends in an empty node there is no aux leaf
+            let mp_aux = mt.generate_non_existence_proof([1; 32]).unwrap();
+            assert_eq!(mp, mp_aux);
+        }
+    }
+
+    #[test]
+    fn test_generate_non_existence_proof_with_aux() {
+        for mut sto in storages() {
+            let mut mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            let val = TestValue::new("this is a test leaf".as_bytes().to_vec(), 15);
+            mt.add(&val).unwrap();
+            let val2 = TestValue::new("this is a second test leaf".as_bytes().to_vec(), 15);
+            mt.add(&val2).unwrap();
+            assert_eq!(
+                Err(Error::KeyAlreadyExists),
+                mt.generate_non_existence_proof(val2.hi())
+            );
+
+            // the path of val3 ends in the final node of val2
+            let val3 = TestValue::new("this is a third test leaf".as_bytes().to_vec(), 15);
+            let mp = mt.generate_non_existence_proof(val3.hi()).unwrap();
+            assert!(!mp.existence);
+            let aux = mp.aux.clone().unwrap();
+            assert_eq!(val2.hi(), aux.hi);
+            assert_eq!(val2.ht(), aux.ht);
+            assert_eq!(1, mp.siblings.len());
+            let num_levels = mt.num_levels;
+            let root = mt.root;
+            let v = verify_proof(root, &mp, val3.hi(), constants::EMPTYNODEVALUE, num_levels);
+            assert!(v);
+            let mp = MerkleProof::from_bytes(&mp.to_bytes(), false).unwrap();
+            let v = verify_proof(root, &mp, val3.hi(), constants::EMPTYNODEVALUE, num_levels);
+            assert!(v);
+
+            // the aux leaf can not be the one of hi, nor a leaf out of the tree
+            let v = verify_proof(root, &mp, val2.hi(), constants::EMPTYNODEVALUE, num_levels);
+            assert!(!v);
+            let mut mp_bad = mp.clone();
+            mp_bad.aux = Some(proof::NodeAux {
+                hi: val3.hi(),
+                ht: val3.ht(),
+            });
+            let v = verify_proof(
+                root,
+                &mp_bad,
+                val2.hi(),
+                constants::EMPTYNODEVALUE,
+                num_levels,
+            );
+            assert!(!v);
+        }
     }
 
     #[test]
@@ -619,7 +1471,7 @@ mod tests {
             &hex::decode("7d7c5e8f4b3bf434f3d9d223359c4415e2764dd38de2e025fbf986e976a7ed3d")
                 .unwrap(),
         );
-        let mp = hex::decode("0000000000000000000000000000000000000000000000000000000000000002d45aada6eec346222eaa6b5d3a9260e08c9b62fcf63c72bc05df284de07e6a52").unwrap();
+        let mp = MerkleProof::from_bytes(&hex::decode("0000000000000000000000000000000000000000000000000000000000000002d45aada6eec346222eaa6b5d3a9260e08c9b62fcf63c72bc05df284de07e6a52").unwrap(), true).unwrap();
         let mut hi: [u8; 32] = [0; 32];
         hi.copy_from_slice(
             &hex::decode("786677808ba77bdd9090a969f1ef2cbd1ac5aecd9e654f340500159219106878")
@@ -631,7 +1483,7 @@ mod tests {
                 .unwrap(),
         );
         let v = verify_proof(root, &mp, hi, ht, 140);
-        assert_eq!(true, v);
+        assert!(v);
     }
 
     #[test]
@@ -642,129 +1494,1189 @@ mod tests {
             &hex::decode("8f021d00c39dcd768974ddfe0d21f5d13f7215bea28db1f1cb29842b111332e7")
                 .unwrap(),
         );
-        let mp = hex::decode("0000000000000000000000000000000000000000000000000000000000000004bf8e980d2ed328ae97f65c30c25520aeb53ff837579e392ea1464934c7c1feb9").unwrap();
+        let mp = MerkleProof::from_bytes(&hex::decode("0000000000000000000000000000000000000000000000000000000000000004bf8e980d2ed328ae97f65c30c25520aeb53ff837579e392ea1464934c7c1feb9").unwrap(), false).unwrap();
         let mut hi: [u8; 32] = [0; 32];
         hi.copy_from_slice(
             &hex::decode("a69792a4cff51f40b7a1f7ae596c6ded4aba241646a47538898f17f2a8dff647")
                 .unwrap(),
         );
         let v = verify_proof(root, &mp, hi, constants::EMPTYNODEVALUE, 140);
-        assert_eq!(true, v);
+        assert!(v);
     }
 
     #[test]
     fn test_add_leafs_different_order() {
-        let mut sto1 = db::Db::new("test".to_string(), true);
-        let mut mt1 = MerkleTree::new(&mut sto1, 140);
-        mt1.add(&TestValue {
-            bytes: "0 this is a test leaf".as_bytes().to_vec(),
-            index_length: 15,
-        })
-        .unwrap();
-        mt1.add(&TestValue {
-            bytes: "1 this is a test leaf".as_bytes().to_vec(),
-            index_length: 15,
-        })
-        .unwrap();
-        mt1.add(&TestValue {
-            bytes: "2 this is a test leaf".as_bytes().to_vec(),
-            index_length: 15,
-        })
-        .unwrap();
-        mt1.add(&TestValue {
-            bytes: "3 this is a test leaf".as_bytes().to_vec(),
-            index_length: 15,
-        })
-        .unwrap();
-        mt1.add(&TestValue {
-            bytes: "4 this is a test leaf".as_bytes().to_vec(),
-            index_length: 15,
-        })
-        .unwrap();
-        mt1.add(&TestValue {
-            bytes: "5 this is a test leaf".as_bytes().to_vec(),
-            index_length: 15,
-        })
-        .unwrap();
-        // mt1.print_full_tree();
+        for (mut sto1, mut sto2) in storages().into_iter().zip(storages()) {
+            let mut mt1 = MerkleTree::new(&mut *sto1, 140).unwrap();
+            mt1.add(&TestValue::new(
+                "0 this is a test leaf".as_bytes().to_vec(),
+                15,
+            ))
+            .unwrap();
+            mt1.add(&TestValue::new(
+                "1 this is a test leaf".as_bytes().to_vec(),
+                15,
+            ))
+            .unwrap();
+            mt1.add(&TestValue::new(
+                "2 this is a test leaf".as_bytes().to_vec(),
+                15,
+            ))
+            .unwrap();
+            mt1.add(&TestValue::new(
+                "3 this is a test leaf".as_bytes().to_vec(),
+                15,
+            ))
+            .unwrap();
+            mt1.add(&TestValue::new(
+                "4 this is a test leaf".as_bytes().to_vec(),
+                15,
+            ))
+            .unwrap();
+            mt1.add(&TestValue::new(
+                "5 this is a test leaf".as_bytes().to_vec(),
+                15,
+            ))
+            .unwrap();
+            // mt1.print_full_tree();
 
-        let mut sto2 = db::Db::new("test".to_string(), true);
-        let mut mt2 = MerkleTree::new(&mut sto2, 140);
-        mt2.add(&TestValue {
-            bytes: "2 this is a test leaf".as_bytes().to_vec(),
-            index_length: 15,
-        })
-        .unwrap();
-        mt2.add(&TestValue {
-            bytes: "1 this is a test leaf".as_bytes().to_vec(),
-            index_length: 15,
-        })
-        .unwrap();
-        mt2.add(&TestValue {
-            bytes: "0 this is a test leaf".as_bytes().to_vec(),
-            index_length: 15,
-        })
-        .unwrap();
-        mt2.add(&TestValue {
-            bytes: "5 this is a test leaf".as_bytes().to_vec(),
-            index_length: 15,
-        })
-        .unwrap();
-        mt2.add(&TestValue {
-            bytes: "3 this is a test leaf".as_bytes().to_vec(),
-            index_length: 15,
-        })
-        .unwrap();
-        mt2.add(&TestValue {
-            bytes: "4 this is a test leaf".as_bytes().to_vec(),
-            index_length: 15,
-        })
+            let mut mt2 = MerkleTree::new(&mut *sto2, 140).unwrap();
+            mt2.add(&TestValue::new(
+                "2 this is a test leaf".as_bytes().to_vec(),
+                15,
+            ))
+            .unwrap();
+            mt2.add(&TestValue::new(
+                "1 this is a test leaf".as_bytes().to_vec(),
+                15,
+            ))
+            .unwrap();
+            mt2.add(&TestValue::new(
+                "0 this is a test leaf".as_bytes().to_vec(),
+                15,
+            ))
+            .unwrap();
+            mt2.add(&TestValue::new(
+                "5 this is a test leaf".as_bytes().to_vec(),
+                15,
+            ))
+            .unwrap();
+            mt2.add(&TestValue::new(
+                "3 this is a test leaf".as_bytes().to_vec(),
+                15,
+            ))
+            .unwrap();
+            mt2.add(&TestValue::new(
+                "4 this is a test leaf".as_bytes().to_vec(),
+                15,
+            ))
+            .unwrap();
+            // mt2.print_full_tree();
+
+            assert_eq!(mt1.root, mt2.root);
+            assert_eq!(
+                &mt1.root.to_hex(),
+                "264397f84da141b3134dcde1d7540d27a2bf0d787bbe8365d9ad5c9c18d3c621"
+            );
+        }
+    }
+
+    #[test]
+    fn test_delete() {
+        for deleted in 0..8 {
+            for (mut sto1, mut sto2) in storages().into_iter().zip(storages()) {
+                let mut mt1 = MerkleTree::new(&mut *sto1, 140).unwrap();
+                let mut mt2 = MerkleTree::new(&mut *sto2, 140).unwrap();
+                for i in 0..8 {
+                    mt1.add(&val(i)).unwrap();
+                    if i != deleted {
+                        mt2.add(&val(i)).unwrap();
+                    }
+                }
+                mt1.delete(val(deleted).hi()).unwrap();
+                assert_eq!(mt1.root, mt2.root);
+
+                // the tree keeps working after the deletion
+                mt1.add(&val(deleted)).unwrap();
+                mt2.add(&val(deleted)).unwrap();
+                assert_eq!(mt1.root, mt2.root);
+                let mp = mt1.generate_proof(val(deleted).hi()).unwrap();
+                assert!(verify_proof(
+                    mt1.root,
+                    &mp,
+                    val(deleted).hi(),
+                    val(deleted).ht(),
+                    mt1.num_levels
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn test_delete_all_leafs() {
+        for mut sto in storages() {
+            let mut mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            let leafs = vals(10);
+            for leaf in &leafs {
+                mt.add(leaf).unwrap();
+            }
+            assert!(mt.delete([1; 32]).is_err());
+            for leaf in leafs.iter().rev() {
+                mt.delete(leaf.hi()).unwrap();
+                assert!(mt.delete(leaf.hi()).is_err());
+            }
+            assert_eq!(constants::EMPTYNODEVALUE, mt.root);
+        }
+    }
+
+    #[test]
+    fn test_update() {
+        for (mut sto1, mut sto2) in storages().into_iter().zip(storages()) {
+            let mut mt1 = MerkleTree::new(&mut *sto1, 140).unwrap();
+            let mut mt2 = MerkleTree::new(&mut *sto2, 140).unwrap();
+            for i in 0..8 {
+                mt1.add(&val(i)).unwrap();
+                let data = if i == 5 {
+                    " this is a test updated"
+                } else {
+                    " this is a test leaf"
+                };
+                mt2.add(&TestValue::new(
+                    (i.to_string() + data).as_bytes().to_vec(),
+                    15,
+                ))
+                .unwrap();
+            }
+            assert_ne!(mt1.root, mt2.root);
+
+            let val = TestValue::new("5 this is a test updated".as_bytes().to_vec(), 15);
+            let old = mt1.update(&val).unwrap();
+            assert_eq!("5 this is a test leaf".as_bytes().to_vec(), old);
+            assert_eq!(*val.bytes(), mt1.get_value_in_pos(val.hi()).unwrap());
+            assert_eq!(mt1.root, mt2.root);
+
+            let mp = mt1.generate_proof(val.hi()).unwrap();
+            assert!(verify_proof(
+                mt1.root,
+                &mp,
+                val.hi(),
+                val.ht(),
+                mt1.num_levels
+            ));
+
+            // the index of the value must already be in the tree
+            let val = TestValue::new("9 this is a test leaf".as_bytes().to_vec(), 15);
+            assert!(mt1.update(&val).is_err());
+        }
+    }
+
+    // Value with an index that is not a prefix of its bytes, to check that
+    // the tree relies on hi/ht and not on the raw bytes.
+    struct KeyValue {
+        bytes: Vec<u8>,
+        key: u64,
+    }
+    impl Value for KeyValue {
+        fn bytes(&self) -> &Vec<u8> {
+            &self.bytes
+        }
+        fn index_length(&self) -> u32 {
+            0
+        }
+        fn hi(&self) -> [u8; 32] {
+            utils::hash_vec(self.key.to_le_bytes().to_vec())
+        }
+        fn ht(&self) -> [u8; 32] {
+            let mut b = self.key.to_le_bytes().to_vec();
+            b.extend(&self.bytes);
+            utils::hash_vec(b)
+        }
+    }
+
+    #[test]
+    fn test_custom_value() {
+        let vals: Vec<KeyValue> = (0..16)
+            .map(|i| KeyValue {
+                bytes: format!("value {}", i).as_bytes().to_vec(),
+                key: i,
+            })
+            .collect();
+        for (mut sto1, mut sto2) in storages().into_iter().zip(storages()) {
+            let mut mt1 = MerkleTree::new(&mut *sto1, 140).unwrap();
+            let mut mt2 = MerkleTree::new(&mut *sto2, 140).unwrap();
+            for v in vals.iter() {
+                mt1.add(v).unwrap();
+            }
+            for v in vals.iter().rev() {
+                mt2.add(v).unwrap();
+            }
+            assert_eq!(mt1.root, mt2.root);
+            assert_eq!(Err(Error::KeyAlreadyExists), mt1.add(&vals[3]));
+
+            for v in vals.iter() {
+                assert_eq!(*v.bytes(), mt1.get_value_in_pos(v.hi()).unwrap());
+                let mp = mt1.generate_proof(v.hi()).unwrap();
+                assert!(verify_value_proof(mt1.root, &mp, v, mt1.num_levels));
+            }
+
+            let updated = KeyValue {
+                bytes: "updated".as_bytes().to_vec(),
+                key: 7,
+            };
+            assert_eq!(vals[7].bytes, mt1.update(&updated).unwrap());
+            let mp = mt1.generate_proof(updated.hi()).unwrap();
+            assert!(verify_value_proof(mt1.root, &mp, &updated, mt1.num_levels));
+            assert!(!verify_value_proof(mt1.root, &mp, &vals[7], mt1.num_levels));
+
+            for v in vals.iter() {
+                mt2.delete(v.hi()).unwrap();
+            }
+            assert_eq!(constants::EMPTYNODEVALUE, mt2.root);
+        }
+    }
+
+    fn check_tree_with_hasher<H: Hasher>() -> [u8; 32] {
+        let vals: Vec<TestValue<H>> = (0..16).map(val_with_hasher).collect();
+        let mut roots = Vec::new();
+        for (mut sto1, mut sto2) in storages().into_iter().zip(storages()) {
+            let mut mt1 = MerkleTree::<_, H>::with_hasher(&mut *sto1, 140).unwrap();
+            let mut mt2 = MerkleTree::<_, H>::with_hasher(&mut *sto2, 140).unwrap();
+            for v in vals.iter() {
+                mt1.add(v).unwrap();
+            }
+            for v in vals.iter().rev() {
+                mt2.add(v).unwrap();
+            }
+            assert_eq!(mt1.root, mt2.root);
+
+            for v in vals.iter() {
+                let mp = mt1.generate_proof(v.hi()).unwrap();
+                assert!(verify_proof_with_hasher::<H>(
+                    mt1.root,
+                    &mp,
+                    v.hi(),
+                    v.ht(),
+                    mt1.num_levels
+                ));
+            }
+            let root = mt1.root;
+            let mt3 = MerkleTree::<_, H>::with_hasher(&mut *sto1, 140).unwrap();
+            assert_eq!(root, mt3.root);
+            roots.push(root);
+        }
+        assert_eq!(roots[0], roots[1]);
+        roots[0]
+    }
+
+    #[test]
+    fn test_hashers() {
+        let keccak_root = check_tree_with_hasher::<Keccak256>();
+        let sha256_root = check_tree_with_hasher::<Sha256>();
+        let blake2b_root = check_tree_with_hasher::<Blake2b>();
+        assert_ne!(keccak_root, sha256_root);
+        assert_ne!(keccak_root, blake2b_root);
+        assert_ne!(sha256_root, blake2b_root);
+
+        // the default hasher is keccak
+        let mut sto = db::MemoryDb::new();
+        let mut mt = MerkleTree::new(&mut sto, 140).unwrap();
+        for i in 0..16 {
+            mt.add(&val(i)).unwrap();
+        }
+        assert_eq!(keccak_root, mt.root);
+
+        // proofs of a tree can not be verified with another hasher
+        let mut sto = db::MemoryDb::new();
+        let mut mt = MerkleTree::<_, Sha256>::with_hasher(&mut sto, 140).unwrap();
+        let val: TestValue<Sha256> =
+            TestValue::with_hasher("this is a test leaf".as_bytes().to_vec(), 15);
+        mt.add(&val).unwrap();
+        mt.add(&TestValue::<Sha256>::with_hasher(
+            "this is another test leaf".as_bytes().to_vec(),
+            15,
+        ))
         .unwrap();
-        // mt2.print_full_tree();
+        let mp = mt.generate_proof(val.hi()).unwrap();
+        assert!(verify_proof_with_hasher::<Sha256>(
+            mt.root,
+            &mp,
+            val.hi(),
+            val.ht(),
+            mt.num_levels
+        ));
+        assert!(!verify_proof(
+            mt.root,
+            &mp,
+            val.hi(),
+            val.ht(),
+            mt.num_levels
+        ));
+    }
 
-        assert_eq!(mt1.root, mt2.root);
-        assert_eq!(
-            &mt1.root.to_hex(),
-            "264397f84da141b3134dcde1d7540d27a2bf0d787bbe8365d9ad5c9c18d3c621"
-        );
+    // leaf as in the iden3 sparse merkle trees, poseidon(key, value, 1)
+    #[cfg(feature = "poseidon")]
+    struct Iden3Leaf {
+        bytes: Vec<u8>,
+    }
+    #[cfg(feature = "poseidon")]
+    impl Iden3Leaf {
+        fn new(key: u64, value: u64) -> Iden3Leaf {
+            let mut bytes = vec![0; 64];
+            bytes[24..32].copy_from_slice(&key.to_be_bytes());
+            bytes[56..].copy_from_slice(&value.to_be_bytes());
+            Iden3Leaf { bytes }
+        }
+    }
+    #[cfg(feature = "poseidon")]
+    impl Value for Iden3Leaf {
+        fn bytes(&self) -> &Vec<u8> {
+            &self.bytes
+        }
+        fn index_length(&self) -> u32 {
+            32
+        }
+        fn hi(&self) -> [u8; 32] {
+            *array_ref!(self.bytes, 0, 32)
+        }
+        fn ht(&self) -> [u8; 32] {
+            Poseidon::hash_leaf(
+                array_ref!(self.bytes, 0, 32),
+                array_ref!(self.bytes, 32, 32),
+            )
+        }
+    }
+
+    #[cfg(feature = "poseidon")]
+    #[test]
+    fn test_poseidon_iden3_roots() {
+        // roots of the go-merkletree (iden3) tests
+        let leafs = [
+            (
+                Iden3Leaf::new(1, 2),
+                "1e05682c815341647510bf582454cca025584699f2419cbdea3205afb3506e5b",
+            ),
+            (
+                Iden3Leaf::new(33, 44),
+                "0bf74e6725aec1f75572ad42659f047eaabd72f9a139980b4b33d3b9b9265c35",
+            ),
+            (
+                Iden3Leaf::new(1234, 9876),
+                "1f67757a73a24f89b6adaa36d780ce723f8c26e24d283a4a39bdf4a444b440a4",
+            ),
+        ];
+        for mut sto in storages() {
+            let mut mt = MerkleTree::<_, Poseidon>::with_hasher(&mut *sto, 141).unwrap();
+            for (leaf, root) in leafs.iter() {
+                mt.add(leaf).unwrap();
+                assert_eq!(*root, mt.root.to_hex());
+            }
+            for (leaf, _) in leafs.iter() {
+                let mp = mt.generate_proof(leaf.hi()).unwrap();
+                assert!(verify_proof_with_hasher::<Poseidon>(
+                    mt.root,
+                    &mp,
+                    leaf.hi(),
+                    leaf.ht(),
+                    mt.num_levels
+                ));
+            }
+
+            // a non existence proof for a path holding another leaf needs the aux
+            let missing = Iden3Leaf::new(65, 1);
+            let mp = mt.generate_proof(missing.hi()).unwrap();
+            assert!(mp.aux.is_some());
+            assert!(verify_proof_with_hasher::<Poseidon>(
+                mt.root,
+                &mp,
+                missing.hi(),
+                constants::EMPTYNODEVALUE,
+                mt.num_levels
+            ));
+
+            mt.delete(leafs[2].0.hi()).unwrap();
+            assert_eq!(leafs[1].1, mt.root.to_hex());
+            mt.delete(leafs[1].0.hi()).unwrap();
+            assert_eq!(leafs[0].1, mt.root.to_hex());
+        }
+    }
+
+    // storage that fails every write once `writes_left` reaches 0, as if the
+    // process had crashed there
+    struct CrashingStorage {
+        sto: db::MemoryDb,
+        writes_left: usize,
+    }
+    impl CrashingStorage {
+        fn write(&mut self) -> Result<()> {
+            if self.writes_left == 0 {
+                return Err(Error::StorageError("crashed".to_string()));
+            }
+            self.writes_left -= 1;
+            Ok(())
+        }
+    }
+    impl Storage for CrashingStorage {
+        fn get_raw(&mut self, k: &[u8; 32]) -> Result<Option<Vec<u8>>> {
+            self.sto.get_raw(k)
+        }
+        fn insert_raw(&mut self, k: [u8; 32], v: Vec<u8>) -> Result<()> {
+            self.write()?;
+            self.sto.insert_raw(k, v)
+        }
+        fn delete(&mut self, k: &[u8; 32]) -> Result<()> {
+            self.write()?;
+            self.sto.delete(k)
+        }
+        fn batch(&mut self, b: db::Batch) -> Result<()> {
+            self.write()?;
+            self.sto.batch(b)
+        }
+        fn keys(&mut self) -> Result<Vec<[u8; 32]>> {
+            self.sto.keys()
+        }
+    }
+
+    #[test]
+    fn test_crash_keeps_root_consistent() {
+        let ops = |mt: &mut MerkleTree<CrashingStorage>, n: usize| -> Result<()> {
+            for i in 0..n as u32 {
+                match i {
+                    8 => mt
+                        .update(&TestValue::new(
+                            "3 this is a test updated".as_bytes().to_vec(),
+                            15,
+                        ))
+                        .map(|_| ())?,
+                    9 => mt.delete(val(5).hi())?,
+                    _ => mt.add(&val(i))?,
+                }
+            }
+            Ok(())
+        };
+        let n_ops = 12;
+
+        for crash_at in 0..n_ops {
+            let mut sto = CrashingStorage {
+                sto: db::MemoryDb::new(),
+                writes_left: crash_at,
+            };
+            let mut mt = MerkleTree::new(&mut sto, 140).unwrap();
+            assert!(ops(&mut mt, n_ops).is_err());
+            let root = mt.root;
+
+            // the tree that would have been built by the ops done before the crash
+            let mut sto_ok = CrashingStorage {
+                sto: db::MemoryDb::new(),
+                writes_left: crash_at,
+            };
+            let mut mt_ok = MerkleTree::new(&mut sto_ok, 140).unwrap();
+            ops(&mut mt_ok, crash_at).unwrap();
+            assert_eq!(mt_ok.root, root);
+
+            // the persisted root is the same and all its nodes are there
+            sto.writes_left = n_ops;
+            let mut mt = MerkleTree::new(&mut sto, 140).unwrap();
+            assert_eq!(root, mt.root);
+            for i in 0..crash_at.min(8) as u32 {
+                if i == 5 && crash_at > 9 {
+                    continue;
+                }
+                let v = if i == 3 && crash_at > 8 {
+                    TestValue::new("3 this is a test updated".as_bytes().to_vec(), 15)
+                } else {
+                    val(i)
+                };
+                let mp = mt.generate_proof(v.hi()).unwrap();
+                assert!(verify_proof(mt.root, &mp, v.hi(), v.ht(), mt.num_levels));
+            }
+            // and the tree keeps working after the crash
+            mt.add(&val(20)).unwrap();
+        }
     }
 
     #[test]
     fn test_add_1000_leafs() {
-        let mut sto = db::Db::new("test".to_string(), true);
-        let mut mt = MerkleTree::new(&mut sto, 140);
-        for i in 0..1000 {
-            mt.add(&TestValue {
-                bytes: (i.to_string() + " this is a test leaf").as_bytes().to_vec(),
-                index_length: 15,
-            })
-            .unwrap();
+        for mut sto in storages() {
+            let mut mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            for i in 0..1000 {
+                mt.add(&val(i)).unwrap();
+            }
+            assert_eq!(
+                mt.root.to_hex(),
+                "6e2da580b2920cd78ed8d4e4bf41e209dfc99ef28bc19560042f0ac803e0d6f7"
+            );
         }
+    }
+
+    #[test]
+    fn test_add_batch() {
+        let vals = vals(1000);
+        for mut sto in storages() {
+            let mut mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            mt.add_batch::<TestValue>(&[]).unwrap();
+            assert_eq!(constants::EMPTYNODEVALUE, mt.root);
+            mt.add_batch(&vals).unwrap();
+            assert_eq!(
+                mt.root.to_hex(),
+                "6e2da580b2920cd78ed8d4e4bf41e209dfc99ef28bc19560042f0ac803e0d6f7"
+            );
+            for v in vals.iter().step_by(97) {
+                assert_eq!(v.bytes, mt.get_value_in_pos(v.hi()).unwrap());
+                let mp = mt.generate_proof(v.hi()).unwrap();
+                assert!(verify_proof(mt.root, &mp, v.hi(), v.ht(), mt.num_levels));
+            }
+        }
+
+        // over a tree that already has leafs, in several batches
+        for mut sto in storages() {
+            let mut mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            for v in vals[..10].iter() {
+                mt.add(v).unwrap();
+            }
+            mt.add_batch(&vals[10..11]).unwrap();
+            mt.add_batch(&vals[11..500]).unwrap();
+            mt.add_batch(&vals[500..]).unwrap();
+            assert_eq!(
+                mt.root.to_hex(),
+                "6e2da580b2920cd78ed8d4e4bf41e209dfc99ef28bc19560042f0ac803e0d6f7"
+            );
+            // and the tree keeps working after
+            mt.delete(vals[3].hi()).unwrap();
+            mt.add(&vals[3]).unwrap();
+            assert_eq!(
+                mt.root.to_hex(),
+                "6e2da580b2920cd78ed8d4e4bf41e209dfc99ef28bc19560042f0ac803e0d6f7"
+            );
+        }
+    }
+
+    #[test]
+    fn test_add_batch_parallel() {
+        let vals = vals(1000);
+        for mut sto in storages() {
+            let mut mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            mt.add_batch_parallel(&vals, 4).unwrap();
+            assert_eq!(
+                mt.root.to_hex(),
+                "6e2da580b2920cd78ed8d4e4bf41e209dfc99ef28bc19560042f0ac803e0d6f7"
+            );
+            let mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            assert_eq!(
+                mt.root.to_hex(),
+                "6e2da580b2920cd78ed8d4e4bf41e209dfc99ef28bc19560042f0ac803e0d6f7"
+            );
+        }
+        let mut sto = db::MemoryDb::new();
+        let mut mt = MerkleTree::new(&mut sto, 140).unwrap();
+        mt.add_batch(&vals[..100]).unwrap();
+        let root = mt.root;
+        for k in [0, 1, 8, 139] {
+            let mut sto = db::MemoryDb::new();
+            let mut mt = MerkleTree::new(&mut sto, 140).unwrap();
+            mt.add_batch_parallel(&vals[..100], k).unwrap();
+            assert_eq!(root, mt.root);
+        }
+
+        for mut sto in storages() {
+            let mut mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            // few leafs, most of the subtrees are empty or hold a single leaf
+            mt.add_batch_parallel(&vals[..3], 8).unwrap();
+            let mut sto2 = db::MemoryDb::new();
+            let mut mt2 = MerkleTree::new(&mut sto2, 140).unwrap();
+            for v in vals[..3].iter() {
+                mt2.add(v).unwrap();
+            }
+            assert_eq!(mt2.root, mt.root);
+
+            // over a non empty tree
+            mt.add_batch_parallel(&vals[3..], 4).unwrap();
+            assert_eq!(
+                mt.root.to_hex(),
+                "6e2da580b2920cd78ed8d4e4bf41e209dfc99ef28bc19560042f0ac803e0d6f7"
+            );
+            for v in vals.iter().step_by(97) {
+                let mp = mt.generate_proof(v.hi()).unwrap();
+                assert!(verify_proof(mt.root, &mp, v.hi(), v.ht(), mt.num_levels));
+            }
+        }
+
+        let mut sto = db::MemoryDb::new();
+        let mut mt = MerkleTree::new(&mut sto, 140).unwrap();
+        let mut batch: Vec<TestValue> = (0..100).map(val).collect();
+        batch.push(TestValue::new(
+            "7 this is a test leaf repeated".as_bytes().to_vec(),
+            15,
+        ));
         assert_eq!(
-            mt.root.to_hex(),
-            "6e2da580b2920cd78ed8d4e4bf41e209dfc99ef28bc19560042f0ac803e0d6f7"
+            Err(Error::KeyAlreadyExists),
+            mt.add_batch_parallel(&batch, 4)
         );
+        assert_eq!(constants::EMPTYNODEVALUE, mt.root);
+        assert!(sto.is_empty());
     }
 
     #[test]
-    fn test_get_root_in_db() {
-        let mut sto = db::Db::new("test".to_string(), true);
-        let mut mt = MerkleTree::new(&mut sto, 140);
-        for i in 0..10 {
-            mt.add(&TestValue {
-                bytes: (i.to_string() + " this is a test leaf").as_bytes().to_vec(),
-                index_length: 15,
-            })
+    fn test_add_batch_writes() {
+        let vals = vals(200);
+        let mut sto_seq = db::MemoryDb::new();
+        let mut mt_seq = MerkleTree::new(&mut sto_seq, 140).unwrap();
+        for v in vals.iter() {
+            mt_seq.add(v).unwrap();
+        }
+        let mut sto = db::MemoryDb::new();
+        let mut mt = MerkleTree::new(&mut sto, 140).unwrap();
+        mt.add_batch(&vals).unwrap();
+        assert_eq!(mt_seq.root, mt.root);
+        // each node is written once, without the ones left behind by each add
+        assert!(sto.len() * 2 < sto_seq.len());
+    }
+
+    #[test]
+    fn test_add_batch_existing_key() {
+        for mut sto in storages() {
+            let mut mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            mt.add_batch(&vals(5)).unwrap();
+            let root = mt.root;
+
+            // the index of a value must not be in the tree nor repeated in the batch
+            assert_eq!(Err(Error::KeyAlreadyExists), mt.add_batch(&vals(10)[4..]));
+            let mut batch = vals(10);
+            batch.push(TestValue::new(
+                "7 this is a test leaf repeated".as_bytes().to_vec(),
+                15,
+            ));
+            assert_eq!(Err(Error::KeyAlreadyExists), mt.add_batch(&batch[5..]));
+            assert_eq!(root, mt.root);
+            mt.add_batch(&batch[5..10]).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_roots_history() {
+        for mut sto in storages() {
+            let mut mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            let mut roots = Vec::new();
+            for i in 0..4 {
+                mt.add(&val(i)).unwrap();
+                roots.push(mt.root);
+            }
+            mt.delete(val(1).hi()).unwrap();
+            roots.push(mt.root);
+            let mut tx = mt.transaction();
+            tx.add(&val(4)).unwrap();
+            tx.add(&val(5)).unwrap();
+            tx.commit().unwrap();
+            roots.push(mt.root);
+            assert!(mt.add(&val(0)).is_err());
+
+            let entries = mt.roots().unwrap();
+            assert_eq!(roots.len(), entries.len());
+            for (i, (e, root)) in entries.iter().zip(roots.iter()).enumerate() {
+                assert_eq!(i as u64, e.seq);
+                assert_eq!(*root, e.root);
+                assert!(e.timestamp > 0);
+                assert!(i == 0 || entries[i - 1].timestamp <= e.timestamp);
+            }
+
+            // the list is persisted
+            let mut mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            assert_eq!(entries, mt.roots().unwrap());
+
+            // each past root can be opened, with the leafs it had
+            for (i, root) in roots.iter().enumerate().take(5) {
+                let mut mt = MerkleTree::at_root(&mut *sto, *root, 140).unwrap();
+                assert_eq!(*root, mt.get_root());
+                for j in 0..4 {
+                    let v = val(j as u32);
+                    let exists = j <= i && !(j == 1 && i == 4);
+                    let expected = if exists {
+                        v.bytes.clone()
+                    } else {
+                        constants::EMPTYNODEVALUE.to_vec()
+                    };
+                    assert_eq!(expected, mt.get_value_in_pos(v.hi()).unwrap());
+                    let mp = mt.generate_proof(v.hi()).unwrap();
+                    let ht = if exists {
+                        v.ht()
+                    } else {
+                        constants::EMPTYNODEVALUE
+                    };
+                    assert!(verify_proof(*root, &mp, v.hi(), ht, 140));
+                }
+                // the view is read only
+                assert_eq!(Err(Error::ReadOnly), mt.add(&val(9)));
+                assert_eq!(Err(Error::ReadOnly), mt.delete(val(0).hi()));
+                let mut tx = mt.transaction();
+                assert_eq!(Err(Error::ReadOnly), tx.add(&val(9)));
+            }
+            let mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            assert_eq!(roots[5], mt.root);
+            assert_eq!(
+                Some(Error::RootNotFound),
+                MerkleTree::at_root(&mut *sto, [1; 32], 140).err()
+            );
+        }
+    }
+
+    #[test]
+    fn test_prune() {
+        for mut sto in storages() {
+            let mut mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            for i in 0..10 {
+                mt.add(&val(i)).unwrap();
+            }
+            let root1 = mt.root;
+            mt.update(&TestValue::new(
+                "3 this is a test updated".as_bytes().to_vec(),
+                15,
+            ))
             .unwrap();
+            mt.delete(val(4).hi()).unwrap();
+            let root2 = mt.root;
+            for i in 10..20 {
+                mt.add(&val(i)).unwrap();
+            }
+            let root3 = mt.root;
+
+            let proofs = |sto: &mut dyn Storage, root: [u8; 32]| -> Vec<Vec<u8>> {
+                let mut mt = MerkleTree::at_root(sto, root, 140).unwrap();
+                (0..25)
+                    .map(|i| {
+                        let hi = val(i).hi();
+                        let mut b = mt.generate_proof(hi).unwrap().to_bytes();
+                        b.extend(mt.get_value_in_pos(hi).unwrap());
+                        b
+                    })
+                    .collect()
+            };
+            let proofs1 = proofs(&mut *sto, root1);
+            let proofs3 = proofs(&mut *sto, root3);
+            let n_keys = sto.keys().unwrap().len();
+
+            let mut mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            assert_eq!(Err(Error::RootNotFound), mt.prune(&[root1, [1; 32]]));
+            let deleted = mt.prune(&[root1]).unwrap();
+            assert!(deleted > 0);
+            assert_eq!(0, mt.prune(&[root1]).unwrap());
+            assert_eq!(n_keys - deleted, sto.keys().unwrap().len());
+
+            // the kept roots give the same proofs, the others are gone
+            assert_eq!(proofs1, proofs(&mut *sto, root1));
+            assert_eq!(proofs3, proofs(&mut *sto, root3));
+            assert_eq!(
+                Some(Error::RootNotFound),
+                MerkleTree::at_root(&mut *sto, root2, 140).err()
+            );
+
+            // the tree keeps working, and its list of roots is kept
+            let mut mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            assert_eq!(root3, mt.root);
+            assert_eq!(22, mt.roots().unwrap().len());
+            mt.delete(val(12).hi()).unwrap();
+            mt.add(&val(12)).unwrap();
+            assert_eq!(root3, mt.root);
+            mt.prune(&[]).unwrap();
+            assert_eq!(proofs3, proofs(&mut *sto, root3));
+            assert_eq!(
+                Some(Error::RootNotFound),
+                MerkleTree::at_root(&mut *sto, root1, 140).err()
+            );
         }
+    }
+
+    #[test]
+    fn test_trees_sharing_storage() {
+        for mut sto in storages() {
+            let mut mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            mt.add(&val(0)).unwrap();
+            let root = mt.root;
+            let mut mt_a = MerkleTree::with_id(&mut *sto, 140, b"alice").unwrap();
+            assert_eq!(constants::EMPTYNODEVALUE, mt_a.root);
+            for i in 0..5 {
+                mt_a.add(&val(i)).unwrap();
+            }
+            let root_a = mt_a.root;
+            let mut mt_b = MerkleTree::with_id(&mut *sto, 140, b"bob").unwrap();
+            for i in 3..8 {
+                mt_b.add(&val(i)).unwrap();
+            }
+            let root_b = mt_b.root;
+            assert_ne!(root_a, root_b);
+
+            let mut mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            assert_eq!(root, mt.root);
+            assert_eq!(1, mt.roots().unwrap().len());
+            let mut mt_a = MerkleTree::with_id(&mut *sto, 140, b"alice").unwrap();
+            assert_eq!(root_a, mt_a.root);
+            let mt_a_roots: Vec<[u8; 32]> = mt_a.roots().unwrap().iter().map(|e| e.root).collect();
+            assert_eq!(5, mt_a_roots.len());
+            assert_eq!(
+                constants::EMPTYNODEVALUE.to_vec(),
+                mt_a.get_value_in_pos(val(7).hi()).unwrap()
+            );
+            let mut mt_b = MerkleTree::with_id(&mut *sto, 140, b"bob").unwrap();
+            assert_eq!(root_b, mt_b.root);
+
+            // pruning a tree keeps the roots of the others, current and past
+            let bob_roots = mt_b.roots().unwrap();
+            let mut mt_a = MerkleTree::with_id(&mut *sto, 140, b"alice").unwrap();
+            mt_a.delete(val(4).hi()).unwrap();
+            assert!(mt_a.prune(&[]).unwrap() > 0);
+            assert_eq!(
+                Some(Error::RootNotFound),
+                MerkleTree::at_root(&mut *sto, mt_a_roots[1], 140).err()
+            );
+            for (n, e) in bob_roots.iter().enumerate() {
+                let mut mt = MerkleTree::at_root(&mut *sto, e.root, 140).unwrap();
+                let leaves: Vec<iter::LeafItem> = mt.iter().map(|l| l.unwrap()).collect();
+                assert_eq!(n + 1, leaves.len());
+            }
+            for (id, leaves) in [(&b"bob"[..], 3..8), (&b""[..], 0..1)] {
+                let mut mt = MerkleTree::with_id(&mut *sto, 140, id).unwrap();
+                assert_eq!(leaves.len(), mt.iter().count());
+                for i in leaves {
+                    let v = val(i);
+                    let mp = mt.generate_proof(v.hi()).unwrap();
+                    assert!(verify_proof(mt.root, &mp, v.hi(), v.ht(), 140));
+                    assert_eq!(v.bytes, mt.get_value_in_pos(v.hi()).unwrap());
+                }
+            }
+        }
+
+        // with the nodes namespaced too, each tree can be pruned on its own
+        for mut sto in storages() {
+            let mut roots = Vec::new();
+            for id in [&b"alice"[..], &b"bob"[..]] {
+                let mut ns = db::Namespaced::new(&mut *sto, id);
+                let mut mt = MerkleTree::new(&mut ns, 140).unwrap();
+                for i in 0..5 {
+                    mt.add(&val(i)).unwrap();
+                }
+                roots.push(mt.root);
+            }
+            // same leafs, same root
+            assert_eq!(roots[0], roots[1]);
+
+            let mut ns_a = db::Namespaced::new(&mut *sto, b"alice");
+            let mut mt_a = MerkleTree::new(&mut ns_a, 140).unwrap();
+            mt_a.delete(val(0).hi()).unwrap();
+            assert!(mt_a.prune(&[]).unwrap() > 0);
+
+            let mut ns_b = db::Namespaced::new(&mut *sto, b"bob");
+            let mut mt_b = MerkleTree::new(&mut ns_b, 140).unwrap();
+            assert_eq!(roots[1], mt_b.root);
+            for i in 0..5 {
+                let v = val(i);
+                let mp = mt_b.generate_proof(v.hi()).unwrap();
+                assert!(verify_proof(mt_b.root, &mp, v.hi(), v.ht(), 140));
+            }
+        }
+
+        // a named tree next to a namespaced one, as in the README
+        for mut sto in storages() {
+            let mut mt_a = MerkleTree::with_id(&mut *sto, 140, b"alice").unwrap();
+            for i in 0..5 {
+                mt_a.add(&val(i)).unwrap();
+            }
+            let root_a = mt_a.root;
+            let mut sto_b = db::Namespaced::new(&mut *sto, b"bob");
+            let mut mt_b = MerkleTree::new(&mut sto_b, 140).unwrap();
+            mt_b.add_batch(&vals(8)).unwrap();
+            mt_b.delete(val(7).hi()).unwrap();
+            let root_b = mt_b.root;
+            let n_keys_b = sto_b.keys().unwrap().len();
+
+            let mut mt_a = MerkleTree::with_id(&mut *sto, 140, b"alice").unwrap();
+            mt_a.delete(val(0).hi()).unwrap();
+            assert!(mt_a.prune(&[]).unwrap() > 0);
+            let mut sto_b = db::Namespaced::new(&mut *sto, b"bob");
+            assert_eq!(n_keys_b, sto_b.keys().unwrap().len());
+            let mut mt_b = MerkleTree::new(&mut sto_b, 140).unwrap();
+            assert_eq!(root_b, mt_b.root);
+            let leaves: Vec<iter::LeafItem> = mt_b.iter().map(|l| l.unwrap()).collect();
+            assert_eq!(7, leaves.len());
+            assert!(mt_b.prune(&[]).unwrap() > 0);
+
+            let mut mt_a = MerkleTree::with_id(&mut *sto, 140, b"alice").unwrap();
+            assert_ne!(root_a, mt_a.root);
+            for i in 1..5 {
+                let v = val(i);
+                let mp = mt_a.generate_proof(v.hi()).unwrap();
+                assert!(verify_proof(mt_a.root, &mp, v.hi(), v.ht(), 140));
+                assert_eq!(v.bytes, mt_a.get_value_in_pos(v.hi()).unwrap());
+            }
+        }
+
+        // a tree on the storage itself, next to a namespaced one
+        for mut sto in storages() {
+            let mut ns_a = db::Namespaced::new(&mut *sto, b"alice");
+            let mut mt_a = MerkleTree::new(&mut ns_a, 140).unwrap();
+            mt_a.add_batch(&vals(10)).unwrap();
+            let root_a = mt_a.root;
+            let n_keys_a = ns_a.keys().unwrap().len();
+
+            let mut mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            mt.add(&val(0)).unwrap();
+            mt.add(&val(1)).unwrap();
+            let mut mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            mt.delete(val(1).hi()).unwrap();
+            assert!(mt.prune(&[]).unwrap() > 0);
+            let root = mt.root;
+
+            let mut ns_a = db::Namespaced::new(&mut *sto, b"alice");
+            assert_eq!(n_keys_a, ns_a.keys().unwrap().len());
+            let mut mt_a = MerkleTree::new(&mut ns_a, 140).unwrap();
+            assert_eq!(root_a, mt_a.root);
+            for v in vals(10) {
+                assert_eq!(v.bytes, mt_a.get_value_in_pos(v.hi()).unwrap());
+            }
+            mt_a.prune(&[]).unwrap();
+            let mut mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            assert_eq!(root, mt.root);
+            assert_eq!(val(0).bytes, mt.get_value_in_pos(val(0).hi()).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_tree_ids_do_not_collide() {
+        // "roots" is "root" followed by the id "s"
+        for first_default in [true, false] {
+            for mut sto in storages() {
+                let ids: [&[u8]; 2] = if first_default {
+                    [b"", b"s"]
+                } else {
+                    [b"s", b""]
+                };
+                let mut roots = Vec::new();
+                for (i, id) in ids.iter().enumerate() {
+                    let mut mt = MerkleTree::with_id(&mut *sto, 140, id).unwrap();
+                    assert_eq!(constants::EMPTYNODEVALUE, mt.root);
+                    for j in 0..3 + i as u32 {
+                        mt.add(&val(j)).unwrap();
+                    }
+                    roots.push(mt.root);
+                }
+                for (i, id) in ids.iter().enumerate() {
+                    let mut mt = MerkleTree::with_id(&mut *sto, 140, id).unwrap();
+                    assert_eq!(roots[i], mt.root);
+                    assert_eq!(3 + i, mt.roots().unwrap().len());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_older_final_nodes() {
+        // final nodes written by older versions hold only the bytes of the
+        // leaf, with the type TYPENODEFINAL
+        for il in [15, 21, 100] {
+            let val = |i: u32| {
+                let mut b = val(i).bytes;
+                b.resize(100, i as u8);
+                TestValue::new(b, il)
+            };
+            for mut sto in storages() {
+                let mut mt = MerkleTree::new(&mut *sto, 140).unwrap();
+                mt.add(&val(1)).unwrap();
+                let root = mt.get_root();
+                let (_, _, b) = mt.sto.get(&root).unwrap();
+                let leaf =
+                    node::parse_final_node_bytes::<Keccak256>(constants::TYPENODEFINALLEAF, il, b)
+                        .unwrap();
+                mt.sto
+                    .insert(root, constants::TYPENODEFINAL, il, leaf.bytes)
+                    .unwrap();
+
+                let mut mt = MerkleTree::new(&mut *sto, 140).unwrap();
+                assert_eq!(val(1).bytes, mt.get_value_in_pos(val(1).hi()).unwrap());
+                assert_eq!(Error::KeyAlreadyExists, mt.add(&val(1)).err().unwrap());
+                mt.add(&val(2)).unwrap();
+                let mut sto_b = db::MemoryDb::new();
+                let mut mt_b = MerkleTree::new(&mut sto_b, 140).unwrap();
+                mt_b.add(&val(1)).unwrap();
+                mt_b.add(&val(2)).unwrap();
+                assert_eq!(mt_b.get_root(), mt.get_root());
+                assert_eq!(val(1).bytes, mt.get_value_in_pos(val(1).hi()).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        for mut sto in storages() {
+            let mut mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            let stats = mt.stats().unwrap();
+            assert_eq!(TreeStats::default(), stats);
+
+            mt.add(&val(0)).unwrap();
+            let stats = mt.stats().unwrap();
+            assert_eq!(1, stats.leaves);
+            assert_eq!(0, stats.normal_nodes);
+            assert_eq!(1, stats.final_nodes);
+            assert_eq!(1, stats.value_nodes);
+            assert_eq!(0, stats.max_final_depth);
+            // the value, the final node and the root entries
+            assert_eq!(5, stats.store_keys);
+
+            for i in 1..100 {
+                mt.add(&val(i)).unwrap();
+            }
+            mt.delete(val(50).hi()).unwrap();
+            let stats = mt.stats().unwrap();
+            assert_eq!(99, stats.leaves);
+            assert_eq!(99, stats.final_nodes);
+            assert_eq!(99, stats.value_nodes);
+            // a binary tree with 99 leaves has 98 inner nodes, plus the ones
+            // of the branches with a single child
+            assert!(stats.normal_nodes >= 98);
+            assert!(stats.max_final_depth as f64 >= stats.avg_final_depth);
+            assert!(stats.avg_final_depth > 6.0 && stats.avg_final_depth < 20.0);
+            assert_eq!(mt.iter().count(), stats.leaves);
+            let before = (stats.store_keys, stats.store_bytes);
+
+            mt.prune(&[]).unwrap();
+            let stats = mt.stats().unwrap();
+            assert_eq!(99, stats.leaves);
+            assert!(stats.store_keys < before.0);
+            assert!(stats.store_bytes < before.1);
+
+            // a tree with a node missing is not reported as a smaller one
+            let (_, _, b) = mt.sto.get(&mt.root).unwrap();
+            let n = node::parse_node_bytes::<Keccak256>(b).unwrap();
+            mt.sto.delete(&n.child_l).unwrap();
+            assert_eq!(Err(Error::CorruptNode), mt.stats());
+        }
+    }
+
+    fn check_add_with_proof<H: Hasher>() {
+        let val = val_with_hasher::<H>;
+        let verify = verify_transition_with_hasher::<H, TestValue<H>>;
+        let mut sto = db::MemoryDb::new();
+        let mut mt = MerkleTree::<_, H>::with_hasher(&mut sto, 140).unwrap();
+        let mut proofs = Vec::new();
+        for i in 0..30 {
+            let old_root = mt.root;
+            let proof = mt.add_with_proof(&val(i)).unwrap();
+            assert!(verify(old_root, mt.root, &proof, &val(i), 140));
+            // not for other values, roots or proofs
+            assert!(!verify(old_root, mt.root, &proof, &val(i + 1), 140));
+            assert!(!verify(mt.root, old_root, &proof, &val(i), 140));
+            assert!(!verify(old_root, old_root, &proof, &val(i), 140));
+            if let Some((prev_old, prev_new, prev)) = proofs.last() {
+                assert!(!verify(*prev_old, *prev_new, &proof, &val(i), 140));
+                assert!(!verify(old_root, mt.root, prev, &val(i), 140));
+            }
+            proofs.push((old_root, mt.root, proof));
+        }
+        // some of the leaves displaced a final node
+        assert!(proofs.iter().any(|p| p.2.old.aux.is_some()));
+        assert_eq!(Err(Error::KeyAlreadyExists), mt.add_with_proof(&val(3)));
+
+        // a proof showing the leaf in a place that was not empty
+        let (old_root, new_root, mut proof) = proofs.pop().unwrap();
+        let i = proof.old.siblings.len() - 1;
+        proof.old.siblings[i] = [1; 32];
+        assert!(!verify(old_root, new_root, &proof, &val(29), 140));
+    }
+
+    #[test]
+    fn test_add_with_proof() {
+        check_add_with_proof::<Keccak256>();
+        check_add_with_proof::<Sha256>();
+        #[cfg(feature = "poseidon")]
+        check_add_with_proof::<Poseidon>();
+    }
+
+    fn check_multi_proof<H: Hasher>() {
+        let val = val_with_hasher::<H>;
+        let verify = verify_multi_proof_with_hasher::<H>;
+        let leaf = |i: u32| (val(i).hi(), val(i).ht());
+        let mut sto = db::MemoryDb::new();
+        let mut mt = MerkleTree::<_, H>::with_hasher(&mut sto, 140).unwrap();
+        let vs: Vec<TestValue<H>> = (0..100).map(val).collect();
+        mt.add_batch(&vs).unwrap();
+        mt.delete(val(99).hi()).unwrap();
+        let root = mt.root;
+
+        for ids in [vec![5], vec![3, 80], (0..20).collect(), (0..99).collect()] {
+            let his: Vec<[u8; 32]> = ids.iter().map(|i| val(*i).hi()).collect();
+            let mp = mt.generate_multi_proof(&his).unwrap();
+            let mut leaves: Vec<([u8; 32], [u8; 32])> = ids.iter().map(|i| leaf(*i)).collect();
+            assert!(verify(root, &mp, &leaves, 140));
+            // in any order
+            leaves.reverse();
+            assert!(verify(root, &mp, &leaves, 140));
+            let mp = MultiProof::from_bytes(&mp.to_bytes()).unwrap();
+            assert!(verify(root, &mp, &leaves, 140));
+
+            // the shared siblings are only once
+            let n_siblings: usize = his
+                .iter()
+                .map(|hi| mt.generate_proof(*hi).unwrap().siblings.len())
+                .sum();
+            assert!(mp.siblings.len() <= n_siblings);
+            if ids.len() > 2 {
+                assert!(mp.siblings.len() * 2 < n_siblings);
+            }
+
+            assert!(!verify([1; 32], &mp, &leaves, 140));
+            let mut wrong = leaves.clone();
+            wrong[0].1 = val(200).ht();
+            assert!(!verify(root, &mp, &wrong, 140));
+            let mut extra = leaves.clone();
+            extra.push(leaf(99));
+            assert!(!verify(root, &mp, &extra, 140));
+            if leaves.len() > 1 {
+                assert!(!verify(root, &mp, &leaves[1..], 140));
+                let mut dup = leaves.clone();
+                dup[1] = dup[0];
+                assert!(!verify(root, &mp, &dup, 140));
+            }
+            let mut short = mp.clone();
+            short.bits.pop();
+            assert!(!verify(root, &short, &leaves, 140));
+            let mut long = mp.clone();
+            long.siblings.push([1; 32]);
+            assert!(!verify(root, &long, &leaves, 140));
+        }
+
         assert_eq!(
-            mt.root.to_hex(),
-            "9418fd35bae19de4ab033efaf7cc624adf6a42827e39029d8da13288e9c3170d"
-        );
-        let mt2 = MerkleTree::new(&mut sto, 140);
-        assert_eq!(
-            mt2.root.to_hex(),
-            "9418fd35bae19de4ab033efaf7cc624adf6a42827e39029d8da13288e9c3170d"
+            Err(Error::KeyNotFound),
+            mt.generate_multi_proof(&[val(3).hi(), val(99).hi()])
         );
+        assert_eq!(Err(Error::KeyNotFound), mt.generate_multi_proof(&[]));
+        assert!(!verify(
+            root,
+            &mt.generate_multi_proof(&[val(3).hi()]).unwrap(),
+            &[],
+            140
+        ));
+    }
+
+    #[test]
+    fn test_multi_proof() {
+        check_multi_proof::<Keccak256>();
+        check_multi_proof::<Sha256>();
+        #[cfg(feature = "poseidon")]
+        check_multi_proof::<Poseidon>();
+    }
+
+    #[test]
+    fn test_get_root_in_db() {
+        for mut sto in storages() {
+            let mut mt = MerkleTree::new(&mut *sto, 140).unwrap();
+            for i in 0..10 {
+                mt.add(&val(i)).unwrap();
+            }
+            assert_eq!(
+                mt.root.to_hex(),
+                "9418fd35bae19de4ab033efaf7cc624adf6a42827e39029d8da13288e9c3170d"
+            );
+            let mt2 = MerkleTree::new(&mut *sto, 140).unwrap();
+            assert_eq!(
+                mt2.root.to_hex(),
+                "9418fd35bae19de4ab033efaf7cc624adf6a42827e39029d8da13288e9c3170d"
+            );
+        }
     }
 }
@@ -1,20 +1,46 @@
+use core::marker::PhantomData;
+
+#[cfg(feature = "std")]
 use super::constants;
-use super::utils;
+use super::hasher::{Hasher, Keccak256};
+#[cfg(feature = "std")]
+use super::Error;
+#[cfg(feature = "std")]
+use super::Result;
+#[cfg(feature = "std")]
+use super::Value;
 
-pub struct TreeNode {
+pub struct TreeNode<H: Hasher = Keccak256> {
     pub child_l: [u8; 32],
     pub child_r: [u8; 32],
+    hasher: PhantomData<H>,
 }
 
 impl TreeNode {
+    pub fn new(child_l: [u8; 32], child_r: [u8; 32]) -> TreeNode {
+        TreeNode::with_hasher(child_l, child_r)
+    }
+}
+
+impl<H: Hasher> TreeNode<H> {
+    /// Builds a node hashed with `H` instead of the default Keccak-256.
+    pub fn with_hasher(child_l: [u8; 32], child_r: [u8; 32]) -> TreeNode<H> {
+        TreeNode {
+            child_l,
+            child_r,
+            hasher: PhantomData,
+        }
+    }
+    #[cfg(feature = "std")]
     pub fn bytes(&self) -> Vec<u8> {
         concatenate_arrays(&self.child_l, &self.child_r)
     }
     pub fn ht(&self) -> [u8; 32] {
-        utils::hash_vec(self.bytes())
+        H::hash_node(&self.child_l, &self.child_r)
     }
 }
 
+#[cfg(feature = "std")]
 fn concatenate_arrays<T: Clone>(x: &[T], y: &[T]) -> Vec<T> {
     let mut concat = x.to_vec();
     concat.extend_from_slice(y);
@@ -22,33 +48,138 @@ fn concatenate_arrays<T: Clone>(x: &[T], y: &[T]) -> Vec<T> {
     concat
 }
 
-pub fn parse_node_bytes(b: Vec<u8>) -> TreeNode {
+/// Leaf placed in a final node. The final node keeps the hi and ht of the
+/// leaf next to its bytes, so the tree does not depend on how each `Value`
+/// computes them.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Leaf {
+    pub hi: [u8; 32],
+    pub ht: [u8; 32],
+    pub index_length: u32,
+    pub bytes: Vec<u8>,
+}
+
+#[cfg(feature = "std")]
+impl Leaf {
+    pub fn from_value<V: Value + ?Sized>(v: &V) -> Leaf {
+        Leaf {
+            hi: v.hi(),
+            ht: v.ht(),
+            index_length: v.index_length(),
+            bytes: v.bytes().to_vec(),
+        }
+    }
+    pub fn final_node_bytes(&self) -> Vec<u8> {
+        let mut b = concatenate_arrays(&self.hi, &self.ht);
+        b.extend_from_slice(&self.bytes);
+        b
+    }
+}
+
+#[cfg(feature = "std")]
+impl Value for Leaf {
+    fn bytes(&self) -> &Vec<u8> {
+        &self.bytes
+    }
+    fn index_length(&self) -> u32 {
+        self.index_length
+    }
+    fn hi(&self) -> [u8; 32] {
+        self.hi
+    }
+    fn ht(&self) -> [u8; 32] {
+        self.ht
+    }
+}
+
+/// Whether a node of type t is a final node, of the current or the older
+/// layout.
+#[cfg(feature = "std")]
+pub fn is_final(t: u8) -> bool {
+    t == constants::TYPENODEFINAL || t == constants::TYPENODEFINALLEAF
+}
+
+/// Leaf in a final node of type t. The final nodes of the older layout
+/// (`TYPENODEFINAL`) hold only the bytes of the leaf, its hi and ht are
+/// computed from them with `H`, as the older versions did.
+#[cfg(feature = "std")]
+pub fn parse_final_node_bytes<H: Hasher>(t: u8, il: u32, b: Vec<u8>) -> Result<Leaf> {
+    if t == constants::TYPENODEFINAL {
+        if b.len() < il as usize {
+            return Err(Error::CorruptNode);
+        }
+        return Ok(Leaf {
+            hi: H::hash_bytes(&b[..il as usize]),
+            ht: H::hash_bytes(&b),
+            index_length: il,
+            bytes: b,
+        });
+    }
+    if t != constants::TYPENODEFINALLEAF || b.len() < 64 + il as usize {
+        return Err(Error::CorruptNode);
+    }
+    Ok(Leaf {
+        hi: *array_ref!(b, 0, 32),
+        ht: *array_ref!(b, 32, 32),
+        index_length: il,
+        bytes: b[64..].to_vec(),
+    })
+}
+
+#[cfg(feature = "std")]
+pub fn parse_node_bytes<H: Hasher>(b: Vec<u8>) -> Result<TreeNode<H>> {
     if b == constants::EMPTYNODEVALUE {
-        let n = TreeNode {
-            child_l: constants::EMPTYNODEVALUE,
-            child_r: constants::EMPTYNODEVALUE,
-        };
-        return n;
+        let n = TreeNode::with_hasher(constants::EMPTYNODEVALUE, constants::EMPTYNODEVALUE);
+        return Ok(n);
+    }
+    if b.len() != 64 {
+        return Err(Error::CorruptNode);
     }
     let child_l = &b[0..32];
     let child_r = &b[32..];
-    TreeNode {
-        child_l: *array_ref!(child_l, 0, 32),
-        child_r: *array_ref!(child_r, 0, 32),
-    }
+    Ok(TreeNode::with_hasher(
+        *array_ref!(child_l, 0, 32),
+        *array_ref!(child_r, 0, 32),
+    ))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::constants;
     use rustc_hex::ToHex;
 
+    #[cfg(feature = "std")]
     #[test]
-    fn test_hash_vec() {
-        let n = TreeNode {
-            child_l: constants::EMPTYNODEVALUE,
-            child_r: constants::EMPTYNODEVALUE,
+    fn test_final_node_bytes() {
+        let leaf = Leaf {
+            hi: [1; 32],
+            ht: [2; 32],
+            index_length: 1,
+            bytes: vec![3, 4],
         };
+        let b = leaf.final_node_bytes();
+        assert_eq!(66, b.len());
+        let t = constants::TYPENODEFINALLEAF;
+        assert_eq!(
+            leaf,
+            parse_final_node_bytes::<Keccak256>(t, 1, b.clone()).unwrap()
+        );
+        assert!(parse_final_node_bytes::<Keccak256>(t, 3, b.clone()).is_err());
+        assert!(parse_final_node_bytes::<Keccak256>(constants::TYPENODEVALUE, 1, b).is_err());
+
+        // older final nodes hold only the bytes
+        let v = crate::TestValue::new(vec![3, 4, 5], 2);
+        let leaf =
+            parse_final_node_bytes::<Keccak256>(constants::TYPENODEFINAL, 2, v.bytes.clone());
+        assert_eq!(Leaf::from_value(&v), leaf.unwrap());
+        assert!(parse_final_node_bytes::<Keccak256>(constants::TYPENODEFINAL, 4, v.bytes).is_err());
+    }
+
+    #[test]
+    fn test_hash_vec() {
+        let n = TreeNode::new(constants::EMPTYNODEVALUE, constants::EMPTYNODEVALUE);
         assert_eq!(
             "ad3228b676f7d3cd4284a5443f17f1962b36e491b30a40b2405849e597ba5fb5",
             n.ht().to_hex()
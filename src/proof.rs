@@ -0,0 +1,762 @@
+use super::constants;
+use super::error::ProofError;
+use super::hasher::{Hasher, Keccak256};
+use super::utils;
+#[cfg(feature = "std")]
+use super::Error;
+#[cfg(feature = "std")]
+use super::Result;
+#[cfg(feature = "std")]
+use super::Value;
+
+/// Leaf found in the path of the index of a proof of non existence.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeAux {
+    pub hi: [u8; 32],
+    pub ht: [u8; 32],
+}
+
+/// Proof borrowing its siblings, which can be decoded and verified without
+/// allocating, and so without the `std` feature. See `MerkleProof` for its
+/// fields, the siblings are here one after the other, 32 bytes each.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProofView<'a> {
+    pub existence: bool,
+    pub empties: [u8; 32],
+    pub siblings: &'a [u8],
+    pub aux: Option<NodeAux>,
+}
+
+impl<'a> ProofView<'a> {
+    /// Decodes a proof in the format of `MerkleProof::to_bytes`, borrowing
+    /// the siblings from the bytes.
+    pub fn from_bytes(b: &'a [u8], existence: bool) -> core::result::Result<Self, ProofError> {
+        if b.len() < 32 {
+            return Err(ProofError::Truncated);
+        }
+        if !b.len().is_multiple_of(32) {
+            return Err(ProofError::InvalidLength(b.len()));
+        }
+        let n_siblings = count_bits(&b[..32]);
+        let siblings_end = 32 + n_siblings * 32;
+        if b.len() < siblings_end {
+            return Err(ProofError::SiblingCountMismatch {
+                bits: n_siblings,
+                siblings: (b.len() - 32) / 32,
+            });
+        }
+        // only a proof of non existence can be followed by an aux leaf
+        let trailing = b.len() - siblings_end;
+        if trailing != 0 && (existence || trailing != 64) {
+            return Err(ProofError::TrailingBytes(trailing));
+        }
+        let mut aux = None;
+        if trailing != 0 {
+            aux = Some(NodeAux {
+                hi: *array_ref!(b, siblings_end, 32),
+                ht: *array_ref!(b, siblings_end + 32, 32),
+            });
+        }
+        Ok(ProofView {
+            existence,
+            empties: *array_ref!(b, 0, 32),
+            siblings: &b[32..siblings_end],
+            aux,
+        })
+    }
+
+    /// Checks that there is a sibling for each bit of `empties`, all of them
+    /// for depths of a tree of `num_levels`.
+    pub fn validate(&self, num_levels: u32) -> core::result::Result<(), ProofError> {
+        if let Some(depth) =
+            (num_levels.saturating_sub(1)..256).find(|d| !self.is_empty_sibling(*d))
+        {
+            return Err(ProofError::DepthOutOfRange(depth));
+        }
+        if !self.siblings.len().is_multiple_of(32) {
+            return Err(ProofError::InvalidLength(self.siblings.len()));
+        }
+        let n_siblings = count_bits(&self.empties);
+        if n_siblings != self.siblings.len() / 32 {
+            return Err(ProofError::SiblingCountMismatch {
+                bits: n_siblings,
+                siblings: self.siblings.len() / 32,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn is_empty_sibling(&self, depth: u32) -> bool {
+        is_empty_sibling(&self.empties, depth)
+    }
+
+    // the i-th of the non empty siblings, from the deepest one
+    fn sibling(&self, i: usize) -> [u8; 32] {
+        *array_ref!(self.siblings, i * 32, 32)
+    }
+}
+
+fn is_empty_sibling(empties: &[u8; 32], depth: u32) -> bool {
+    (empties[empties.len() - depth as usize / 8 - 1] & (1 << (depth % 8))) == 0
+}
+
+/// Proof of the value placed in the position of a leaf index (hi), for a
+/// given root.
+///
+/// Bit `i` of `empties` is set when the sibling at depth `i` (the root
+/// children are at depth 0) is not empty, in that case the sibling is part of
+/// `siblings`, which go from the deepest one to the one closest to the root.
+///
+/// A proof of non existence can carry in `aux` the leaf that is placed in the
+/// path of the index, then the siblings are the ones of the `aux` leaf.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct MerkleProof {
+    pub existence: bool,
+    pub empties: [u8; 32],
+    pub siblings: Vec<[u8; 32]>,
+    pub aux: Option<NodeAux>,
+}
+
+#[cfg(feature = "std")]
+impl MerkleProof {
+    /// Encodes the proof as the 32 bytes of `empties` followed by the
+    /// siblings, the same format used by the Go version. When there is an
+    /// `aux` leaf, its hi and ht are appended at the end.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut b: Vec<u8> = self.empties.to_vec();
+        for s in &self.siblings {
+            b.extend_from_slice(s);
+        }
+        if let Some(aux) = &self.aux {
+            b.extend_from_slice(&aux.hi);
+            b.extend_from_slice(&aux.ht);
+        }
+        b
+    }
+
+    /// Decodes a proof encoded by `to_bytes`. The existence flag is not part
+    /// of the encoding, so it has to be provided by the caller. Bytes that
+    /// are not a proof give an `Error::MalformedProof`, telling why.
+    pub fn from_bytes(b: &[u8], existence: bool) -> Result<MerkleProof> {
+        let view = ProofView::from_bytes(b, existence).map_err(Error::MalformedProof)?;
+        Ok(MerkleProof {
+            existence,
+            empties: view.empties,
+            siblings: view
+                .siblings
+                .chunks(32)
+                .map(|s| *array_ref!(s, 0, 32))
+                .collect(),
+            aux: view.aux,
+        })
+    }
+
+    /// The proof borrowing its siblings.
+    pub fn view(&self) -> ProofView<'_> {
+        ProofView {
+            existence: self.existence,
+            empties: self.empties,
+            siblings: self.siblings.as_flattened(),
+            aux: self.aux.clone(),
+        }
+    }
+
+    /// Checks that the proof can be one of a tree of `num_levels`: it has a
+    /// sibling for each bit of `empties`, all of them for depths of the
+    /// tree, and only a proof of non existence has an aux leaf.
+    pub fn validate(&self, num_levels: u32) -> Result<()> {
+        if !(2..=257).contains(&num_levels) {
+            return Err(Error::InvalidNumLevels(num_levels));
+        }
+        self.view()
+            .validate(num_levels)
+            .map_err(Error::MalformedProof)?;
+        if self.existence && self.aux.is_some() {
+            return Err(Error::InvalidProof);
+        }
+        Ok(())
+    }
+
+    pub fn is_empty_sibling(&self, depth: u32) -> bool {
+        is_empty_sibling(&self.empties, depth)
+    }
+
+    pub fn set_non_empty_sibling(&mut self, depth: u32) {
+        self.empties[self.empties.len() - depth as usize / 8 - 1] |= 1 << (depth % 8);
+    }
+
+    /// Siblings of all the depths, from the root down, with the empty ones
+    /// as `constants::EMPTYNODEVALUE`. This is the layout of the siblings
+    /// input of the circom sparse merkle tree verifier.
+    pub fn all_siblings(&self, num_levels: u32) -> Vec<[u8; 32]> {
+        let mut non_empty = self.siblings.iter().rev();
+        (0..num_levels - 1)
+            .map(|depth| {
+                if self.is_empty_sibling(depth) {
+                    constants::EMPTYNODEVALUE
+                } else {
+                    *non_empty.next().unwrap_or(&constants::EMPTYNODEVALUE)
+                }
+            })
+            .collect()
+    }
+}
+
+/// Proof that adding a leaf moved a tree from an old root to a new one, see
+/// `MerkleTree::add_with_proof`.
+///
+/// `old` is the proof of non existence of the leaf in the old tree, carrying
+/// in its `aux` the leaf displaced by the new one (if any), and `new` the
+/// proof of the leaf in the new tree.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransitionProof {
+    pub old: MerkleProof,
+    pub new: MerkleProof,
+}
+
+/// Checks that the proof shows that adding the value to the tree of
+/// `old_root` gives the tree of `new_root`.
+#[cfg(feature = "std")]
+pub fn verify_transition<V: Value>(
+    old_root: [u8; 32],
+    new_root: [u8; 32],
+    proof: &TransitionProof,
+    v: &V,
+    num_levels: u32,
+) -> bool {
+    verify_transition_with_hasher::<Keccak256, V>(old_root, new_root, proof, v, num_levels)
+}
+
+/// Same as `verify_transition`, for a tree hashed with `H`.
+#[cfg(feature = "std")]
+pub fn verify_transition_with_hasher<H: Hasher, V: Value>(
+    old_root: [u8; 32],
+    new_root: [u8; 32],
+    proof: &TransitionProof,
+    v: &V,
+    num_levels: u32,
+) -> bool {
+    let (hi, ht) = (v.hi(), v.ht());
+    if proof.old.existence
+        || !verify_proof_with_hasher::<H>(
+            old_root,
+            &proof.old,
+            hi,
+            constants::EMPTYNODEVALUE,
+            num_levels,
+        )
+        || !verify_proof_with_hasher::<H>(new_root, &proof.new, hi, ht, num_levels)
+    {
+        return false;
+    }
+
+    // the new root is the one of the old siblings with the leaf in the
+    // place that was empty, next to the displaced leaf if any
+    let path = utils::get_path(num_levels, hi);
+    let mut mp = proof.old.clone();
+    if let Some(aux) = &proof.old.aux {
+        let path_aux = utils::get_path(num_levels, aux.hi);
+        let pos_diff = utils::compare_paths(&path_aux, &path) as u32;
+        let sibling = utils::calc_hash_from_leaf_and_level::<H>(pos_diff, &path_aux, aux.ht);
+        mp.siblings.insert(0, sibling);
+        mp.set_non_empty_sibling(num_levels - 2 - pos_diff);
+    }
+    root_from_proof::<H>(&mp.view(), &path, ht, num_levels) == new_root
+}
+
+/// Proof of several leaves of a tree at once, see
+/// `MerkleTree::generate_multi_proof`.
+///
+/// The nodes in the paths of the leaves are walked from the root down,
+/// left before right. `bits` has, for each node holding a single one of the
+/// leaves, whether the leaf is placed there, and for each child with none of
+/// them (a sibling), whether it is not empty, in that case the sibling is
+/// part of `siblings`. The siblings shared by the leaves are only once in
+/// the proof, and the nodes above them are computed from the leaves.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct MultiProof {
+    pub bits: Vec<bool>,
+    pub siblings: Vec<[u8; 32]>,
+}
+
+#[cfg(feature = "std")]
+impl MultiProof {
+    /// Encodes the proof as the number of bits (u32 little endian), the bits
+    /// packed in bytes (the first one in the lowest bit) and the siblings.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut b: Vec<u8> = (self.bits.len() as u32).to_le_bytes().to_vec();
+        let mut packed = vec![0; self.bits.len().div_ceil(8)];
+        for (i, bit) in self.bits.iter().enumerate() {
+            if *bit {
+                packed[i / 8] |= 1 << (i % 8);
+            }
+        }
+        b.extend_from_slice(&packed);
+        for s in &self.siblings {
+            b.extend_from_slice(s);
+        }
+        b
+    }
+
+    /// Decodes a proof encoded by `to_bytes`.
+    pub fn from_bytes(b: &[u8]) -> Result<MultiProof> {
+        if b.len() < 4 {
+            return Err(Error::MalformedProof(ProofError::Truncated));
+        }
+        let n_bits = u32::from_le_bytes(*array_ref!(b, 0, 4)) as usize;
+        let siblings_start = 4 + n_bits.div_ceil(8);
+        if b.len() < siblings_start {
+            return Err(Error::MalformedProof(ProofError::Truncated));
+        }
+        if !(b.len() - siblings_start).is_multiple_of(32) {
+            return Err(Error::MalformedProof(ProofError::InvalidLength(
+                b.len() - siblings_start,
+            )));
+        }
+        let bits = (0..n_bits)
+            .map(|i| b[4 + i / 8] & (1 << (i % 8)) != 0)
+            .collect();
+        let siblings = b[siblings_start..]
+            .chunks(32)
+            .map(|s| *array_ref!(s, 0, 32))
+            .collect();
+        Ok(MultiProof { bits, siblings })
+    }
+}
+
+/// Checks that the multi proof shows all the leaves, given by their hi and
+/// ht, in the tree of the `root`.
+#[cfg(feature = "std")]
+pub fn verify_multi_proof(
+    root: [u8; 32],
+    mp: &MultiProof,
+    leaves: &[([u8; 32], [u8; 32])],
+    num_levels: u32,
+) -> bool {
+    verify_multi_proof_with_hasher::<Keccak256>(root, mp, leaves, num_levels)
+}
+
+/// Same as `verify_multi_proof`, for a tree hashed with `H`.
+#[cfg(feature = "std")]
+pub fn verify_multi_proof_with_hasher<H: Hasher>(
+    root: [u8; 32],
+    mp: &MultiProof,
+    leaves: &[([u8; 32], [u8; 32])],
+    num_levels: u32,
+) -> bool {
+    if !(2..=257).contains(&num_levels)
+        || leaves.is_empty()
+        || leaves.iter().any(|l| l.1 == constants::EMPTYNODEVALUE)
+    {
+        return false;
+    }
+    // the leaves in the order of the walk
+    let mut leaves: Vec<(Vec<bool>, [u8; 32])> = leaves
+        .iter()
+        .map(|l| (utils::get_path(num_levels, l.0), l.1))
+        .collect();
+    leaves.sort_by(|a, b| a.0.iter().rev().cmp(b.0.iter().rev()));
+    if leaves.windows(2).any(|w| w[0].0 == w[1].0) {
+        return false;
+    }
+
+    let mut walk = MultiProofWalk {
+        bits: mp.bits.iter(),
+        siblings: mp.siblings.iter(),
+    };
+    match walk.node::<H>(num_levels - 1, &leaves) {
+        Some(r) => r == root && walk.bits.next().is_none() && walk.siblings.next().is_none(),
+        None => false,
+    }
+}
+
+// consumes the bits and siblings of a multi proof while computing the nodes
+#[cfg(feature = "std")]
+struct MultiProofWalk<'p> {
+    bits: std::slice::Iter<'p, bool>,
+    siblings: std::slice::Iter<'p, [u8; 32]>,
+}
+
+#[cfg(feature = "std")]
+impl MultiProofWalk<'_> {
+    fn sibling(&mut self) -> Option<[u8; 32]> {
+        if *self.bits.next()? {
+            self.siblings.next().cloned()
+        } else {
+            Some(constants::EMPTYNODEVALUE)
+        }
+    }
+
+    // hash of the node at level lvl holding the given (sorted) leaves
+    fn node<H: Hasher>(&mut self, lvl: u32, leaves: &[(Vec<bool>, [u8; 32])]) -> Option<[u8; 32]> {
+        if leaves.len() == 1 && *self.bits.next()? {
+            return Some(utils::calc_hash_from_leaf_and_level::<H>(
+                lvl,
+                &leaves[0].0,
+                leaves[0].1,
+            ));
+        }
+        if lvl == 0 {
+            return None;
+        }
+        let split = leaves.partition_point(|l| !l.0[lvl as usize - 1]);
+        let child_l = if split == 0 {
+            self.sibling()?
+        } else {
+            self.node::<H>(lvl - 1, &leaves[..split])?
+        };
+        let child_r = if split == leaves.len() {
+            self.sibling()?
+        } else {
+            self.node::<H>(lvl - 1, &leaves[split..])?
+        };
+        Some(H::hash_node(&child_l, &child_r))
+    }
+}
+
+fn count_bits(b: &[u8]) -> usize {
+    b.iter().map(|e| e.count_ones() as usize).sum()
+}
+
+/// Checks that the proof shows the leaf with the given `hi` and `ht` in the
+/// tree of the `root`. For a proof of non existence, `ht` must be
+/// `constants::EMPTYNODEVALUE`. A malformed proof (see
+/// `MerkleProof::validate`) is not valid for any root.
+#[cfg(feature = "std")]
+pub fn verify_proof(
+    root: [u8; 32],
+    mp: &MerkleProof,
+    hi: [u8; 32],
+    ht: [u8; 32],
+    num_levels: u32,
+) -> bool {
+    verify_proof_with_hasher::<Keccak256>(root, mp, hi, ht, num_levels)
+}
+
+/// Same as `verify_proof`, for a tree hashed with `H`.
+#[cfg(feature = "std")]
+pub fn verify_proof_with_hasher<H: Hasher>(
+    root: [u8; 32],
+    mp: &MerkleProof,
+    hi: [u8; 32],
+    ht: [u8; 32],
+    num_levels: u32,
+) -> bool {
+    verify_proof_view_with_hasher::<H>(root, &mp.view(), hi, ht, num_levels)
+}
+
+/// Same as `verify_proof`, for a borrowed proof. It does not allocate.
+pub fn verify_proof_view(
+    root: [u8; 32],
+    mp: &ProofView,
+    hi: [u8; 32],
+    ht: [u8; 32],
+    num_levels: u32,
+) -> bool {
+    verify_proof_view_with_hasher::<Keccak256>(root, mp, hi, ht, num_levels)
+}
+
+/// Same as `verify_proof_view`, for a tree hashed with `H`.
+pub fn verify_proof_view_with_hasher<H: Hasher>(
+    root: [u8; 32],
+    mp: &ProofView,
+    hi: [u8; 32],
+    ht: [u8; 32],
+    num_levels: u32,
+) -> bool {
+    if !(2..=257).contains(&num_levels)
+        || mp.existence == (ht == constants::EMPTYNODEVALUE)
+        || (mp.existence && mp.aux.is_some())
+        || mp.validate(num_levels).is_err()
+    {
+        return false;
+    }
+
+    let n = num_levels as usize - 1;
+    let path = utils::get_path_array(num_levels, hi);
+    match &mp.aux {
+        None => root_from_proof::<H>(mp, &path[..n], ht, num_levels) == root,
+        Some(aux) => {
+            // the aux leaf must be in the tree, and the sibling at the depth
+            // where its path and the one of hi diverge (which is the subtree
+            // where hi would be) must be empty
+            let path_aux = utils::get_path_array(num_levels, aux.hi);
+            let pos_diff = utils::compare_paths(&path_aux[..n], &path[..n]);
+            if pos_diff == -1 || !mp.is_empty_sibling(num_levels - 2 - pos_diff as u32) {
+                return false;
+            }
+            root_from_proof::<H>(mp, &path_aux[..n], aux.ht, num_levels) == root
+        }
+    }
+}
+
+/// Checks that the proof shows the value in the tree of the `root`.
+#[cfg(feature = "std")]
+pub fn verify_value_proof<V: Value>(
+    root: [u8; 32],
+    mp: &MerkleProof,
+    v: &V,
+    num_levels: u32,
+) -> bool {
+    verify_proof(root, mp, v.hi(), v.ht(), num_levels)
+}
+
+fn root_from_proof<H: Hasher>(
+    mp: &ProofView,
+    path: &[bool],
+    ht: [u8; 32],
+    num_levels: u32,
+) -> [u8; 32] {
+    let mut node_hash = ht;
+    let mut sibling_used_pos = 0;
+    // the leaf is hashed up (if the hasher does it) once a non empty sibling
+    // is found, as it is the only leaf of the subtree until then
+    let mut single_leaf = ht != constants::EMPTYNODEVALUE;
+
+    for i in (0..=num_levels - 2).rev() {
+        let lvl = num_levels - 2 - i;
+        let sibling: [u8; 32] = if !mp.is_empty_sibling(i) {
+            sibling_used_pos += 1;
+            mp.sibling(sibling_used_pos - 1)
+        } else {
+            constants::EMPTYNODEVALUE
+        };
+
+        if sibling == constants::EMPTYNODEVALUE
+            && (single_leaf || node_hash == constants::EMPTYNODEVALUE)
+        {
+            continue;
+        }
+        if single_leaf {
+            node_hash = utils::calc_hash_from_leaf_and_level::<H>(lvl, path, ht);
+            single_leaf = false;
+        }
+        if path[lvl as usize] {
+            node_hash = H::hash_node(&sibling, &node_hash);
+        } else {
+            node_hash = H::hash_node(&node_hash, &sibling);
+        }
+    }
+    if single_leaf {
+        node_hash = utils::calc_hash_from_leaf_and_level::<H>(num_levels - 1, path, ht);
+    }
+    node_hash
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use rustc_hex::ToHex;
+
+    #[test]
+    fn test_proof_bytes() {
+        let b = hex::decode("0000000000000000000000000000000000000000000000000000000000000003bf8e980d2ed328ae97f65c30c25520aeb53ff837579e392ea1464934c7c1feb9fd8e1a60cdb23c0c7b2cf8462c99fafd905054dccb0ed75e7c8a7d6806749b6b").unwrap();
+        let mp = MerkleProof::from_bytes(&b, false).unwrap();
+        assert!(!mp.existence);
+        assert_eq!(2, mp.siblings.len());
+        assert!(!mp.is_empty_sibling(0));
+        assert!(!mp.is_empty_sibling(1));
+        assert!(mp.is_empty_sibling(2));
+        assert_eq!(
+            "bf8e980d2ed328ae97f65c30c25520aeb53ff837579e392ea1464934c7c1feb9",
+            mp.siblings[0].to_hex()
+        );
+        assert_eq!(b, mp.to_bytes());
+
+        let malformed = |e| Err(Error::MalformedProof(e));
+        assert_eq!(
+            malformed(ProofError::Truncated),
+            MerkleProof::from_bytes(&b[..31], true)
+        );
+        assert_eq!(
+            malformed(ProofError::InvalidLength(40)),
+            MerkleProof::from_bytes(&b[..40], true)
+        );
+        assert_eq!(
+            malformed(ProofError::SiblingCountMismatch {
+                bits: 2,
+                siblings: 1
+            }),
+            MerkleProof::from_bytes(&b[..64], true)
+        );
+        let mut long = b.clone();
+        long.extend_from_slice(&[0; 32]);
+        assert_eq!(
+            malformed(ProofError::TrailingBytes(32)),
+            MerkleProof::from_bytes(&long, false)
+        );
+    }
+
+    #[test]
+    fn test_proof_view() {
+        let root = hex::decode("7d7c5e8f4b3bf434f3d9d223359c4415e2764dd38de2e025fbf986e976a7ed3d")
+            .unwrap();
+        let hi = hex::decode("786677808ba77bdd9090a969f1ef2cbd1ac5aecd9e654f340500159219106878")
+            .unwrap();
+        let b = hex::decode("0000000000000000000000000000000000000000000000000000000000000002d45aada6eec346222eaa6b5d3a9260e08c9b62fcf63c72bc05df284de07e6a52").unwrap();
+        let (root, hi) = (*array_ref!(root, 0, 32), *array_ref!(hi, 0, 32));
+
+        let view = ProofView::from_bytes(&b, true).unwrap();
+        assert_eq!(&b[32..], view.siblings);
+        let mp = MerkleProof::from_bytes(&b, true).unwrap();
+        assert_eq!(mp.view(), view);
+        assert!(verify_proof_view(root, &view, hi, hi, 140));
+        assert!(!verify_proof_view(root, &view, hi, [1; 32], 140));
+        assert_eq!(Ok(()), view.validate(140));
+        assert_eq!(
+            Err(ProofError::Truncated),
+            ProofView::from_bytes(&b[..16], true)
+        );
+
+        // a view over siblings that are not 32 bytes each
+        let view = ProofView {
+            siblings: &b[32..60],
+            ..view
+        };
+        assert_eq!(Err(ProofError::InvalidLength(28)), view.validate(140));
+        assert!(!verify_proof_view(root, &view, hi, hi, 140));
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut mp = MerkleProof {
+            existence: true,
+            empties: [0; 32],
+            siblings: vec![[1; 32]],
+            aux: None,
+        };
+        mp.set_non_empty_sibling(3);
+        assert_eq!(Ok(()), mp.validate(140));
+        assert_eq!(Err(Error::InvalidNumLevels(1)), mp.validate(1));
+        assert_eq!(Err(Error::InvalidNumLevels(258)), mp.validate(258));
+        assert_eq!(
+            Err(Error::MalformedProof(ProofError::DepthOutOfRange(3))),
+            mp.validate(4)
+        );
+        mp.set_non_empty_sibling(7);
+        assert_eq!(
+            Err(Error::MalformedProof(ProofError::SiblingCountMismatch {
+                bits: 2,
+                siblings: 1
+            })),
+            mp.validate(140)
+        );
+        mp.siblings.push([2; 32]);
+        mp.aux = Some(NodeAux {
+            hi: [1; 32],
+            ht: [2; 32],
+        });
+        assert_eq!(Err(Error::InvalidProof), mp.validate(140));
+    }
+
+    #[test]
+    fn test_verify_malformed_proof() {
+        // none of them panics
+        let hi = [3; 32];
+        let ht = [4; 32];
+        let mut mp = MerkleProof {
+            existence: true,
+            empties: [0xff; 32],
+            siblings: vec![[1; 32]],
+            aux: None,
+        };
+        for num_levels in [0, 1, 2, 140, 257, 258] {
+            assert!(!verify_proof([0; 32], &mp, hi, ht, num_levels));
+        }
+        mp.empties = [0; 32];
+        mp.set_non_empty_sibling(200);
+        assert!(!verify_proof([0; 32], &mp, hi, ht, 140));
+        mp.existence = false;
+        mp.aux = Some(NodeAux { hi, ht });
+        assert!(!verify_proof(
+            [0; 32],
+            &mp,
+            hi,
+            constants::EMPTYNODEVALUE,
+            140
+        ));
+
+        let multi = MultiProof {
+            bits: vec![true; 3],
+            siblings: Vec::new(),
+        };
+        for num_levels in [0, 1, 2, 140, 258] {
+            assert!(!verify_multi_proof(
+                [0; 32],
+                &multi,
+                &[(hi, ht)],
+                num_levels
+            ));
+        }
+        for b in [&[1, 0][..], &[200, 0, 0, 0, 1], &[0; 40]] {
+            assert!(MultiProof::from_bytes(b).is_err());
+        }
+    }
+
+    #[test]
+    fn test_proof_bytes_with_aux() {
+        let mp = MerkleProof {
+            existence: false,
+            empties: [0; 32],
+            siblings: Vec::new(),
+            aux: Some(NodeAux {
+                hi: [1; 32],
+                ht: [2; 32],
+            }),
+        };
+        let b = mp.to_bytes();
+        assert_eq!(96, b.len());
+        assert_eq!(mp, MerkleProof::from_bytes(&b, false).unwrap());
+        // a proof of existence can not carry an aux leaf
+        assert!(MerkleProof::from_bytes(&b, true).is_err());
+    }
+
+    #[test]
+    fn test_multi_proof_bytes() {
+        let mp = MultiProof {
+            bits: vec![false, true, true, false, false, false, false, false, true],
+            siblings: vec![[1; 32], [2; 32]],
+        };
+        let b = mp.to_bytes();
+        assert_eq!(4 + 2 + 64, b.len());
+        assert_eq!(vec![9, 0, 0, 0, 0b110, 1], b[..6].to_vec());
+        assert_eq!(mp, MultiProof::from_bytes(&b).unwrap());
+
+        assert_eq!(
+            Err(Error::MalformedProof(ProofError::Truncated)),
+            MultiProof::from_bytes(&b[..3])
+        );
+        assert_eq!(
+            Err(Error::MalformedProof(ProofError::Truncated)),
+            MultiProof::from_bytes(&b[..5])
+        );
+        assert_eq!(
+            Err(Error::MalformedProof(ProofError::InvalidLength(34))),
+            MultiProof::from_bytes(&b[..40])
+        );
+    }
+
+    #[test]
+    fn test_all_siblings() {
+        let mut mp = MerkleProof {
+            existence: true,
+            empties: [0; 32],
+            siblings: vec![[3; 32], [1; 32]],
+            aux: None,
+        };
+        mp.set_non_empty_sibling(0);
+        mp.set_non_empty_sibling(9);
+        let siblings = mp.all_siblings(12);
+        assert_eq!(11, siblings.len());
+        assert_eq!([1; 32], siblings[0]);
+        assert_eq!([3; 32], siblings[9]);
+        for (i, s) in siblings.iter().enumerate() {
+            if i != 0 && i != 9 {
+                assert_eq!(constants::EMPTYNODEVALUE, *s);
+            }
+        }
+    }
+}
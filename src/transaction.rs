@@ -0,0 +1,208 @@
+use std::marker::PhantomData;
+
+use super::db;
+use super::hasher::Hasher;
+use super::MerkleTree;
+use super::Result;
+use super::Value;
+
+/// Group of adds, updates and deletes over a `MerkleTree`, staged in memory
+/// and written all at once on `commit`. Dropping the transaction (or calling
+/// `rollback`) discards them, leaving the tree untouched.
+pub struct Transaction<'t, S: db::Storage + ?Sized, H: Hasher> {
+    overlay: db::Overlay<'t, S>,
+    root: [u8; 32],
+    num_levels: u32,
+    root_node_key: [u8; 32],
+    roots_key: [u8; 32],
+    read_only: bool,
+    tree_root: &'t mut [u8; 32],
+    hasher: PhantomData<H>,
+}
+
+impl<'t, S: db::Storage + ?Sized, H: Hasher> Transaction<'t, S, H> {
+    pub(crate) fn new<'a>(mt: &'t mut MerkleTree<'a, S, H>) -> Transaction<'t, S, H> {
+        Transaction {
+            overlay: db::Overlay::new(&mut *mt.sto),
+            root: mt.root,
+            num_levels: mt.num_levels,
+            root_node_key: mt.root_node_key,
+            roots_key: mt.roots_key,
+            read_only: mt.read_only,
+            tree_root: &mut mt.root,
+            hasher: PhantomData,
+        }
+    }
+
+    // tree over the staged state, its mutations go to the overlay
+    fn tree(&mut self) -> MerkleTree<'_, db::Overlay<'t, S>, H> {
+        MerkleTree {
+            root: self.root,
+            num_levels: self.num_levels,
+            sto: &mut self.overlay,
+            root_node_key: self.root_node_key,
+            roots_key: self.roots_key,
+            read_only: self.read_only,
+            record_roots: false,
+            hasher: PhantomData,
+        }
+    }
+
+    pub fn get_root(&self) -> [u8; 32] {
+        self.root
+    }
+
+    pub fn add<V: Value>(&mut self, v: &V) -> Result<()> {
+        let mut mt = self.tree();
+        mt.add(v)?;
+        self.root = mt.root;
+        Ok(())
+    }
+
+    pub fn add_batch<V: Value>(&mut self, vs: &[V]) -> Result<()> {
+        let mut mt = self.tree();
+        mt.add_batch(vs)?;
+        self.root = mt.root;
+        Ok(())
+    }
+
+    pub fn update<V: Value>(&mut self, v: &V) -> Result<Vec<u8>> {
+        let mut mt = self.tree();
+        let old = mt.update(v)?;
+        self.root = mt.root;
+        Ok(old)
+    }
+
+    pub fn delete(&mut self, hi: [u8; 32]) -> Result<()> {
+        let mut mt = self.tree();
+        mt.delete(hi)?;
+        self.root = mt.root;
+        Ok(())
+    }
+
+    pub fn get_value_in_pos(&mut self, hi: [u8; 32]) -> Result<Vec<u8>> {
+        self.tree().get_value_in_pos(hi)
+    }
+
+    /// Writes all the staged changes, and the new root, in a single batch.
+    pub fn commit(mut self) -> Result<()> {
+        let root = self.root;
+        let mut mt = self.tree();
+        mt.record_roots = true;
+        mt.commit(db::Batch::new(), root)?;
+        self.overlay.commit()?;
+        *self.tree_root = self.root;
+        Ok(())
+    }
+
+    pub fn rollback(self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::val;
+    use crate::{constants, Error, TestValue};
+    use db::Storage;
+
+    // storage counting the writes done to it
+    #[derive(Default)]
+    struct CountingStorage {
+        sto: db::MemoryDb,
+        writes: usize,
+    }
+    impl Storage for CountingStorage {
+        fn get_raw(&mut self, k: &[u8; 32]) -> Result<Option<Vec<u8>>> {
+            self.sto.get_raw(k)
+        }
+        fn insert_raw(&mut self, k: [u8; 32], v: Vec<u8>) -> Result<()> {
+            self.writes += 1;
+            self.sto.insert_raw(k, v)
+        }
+        fn delete(&mut self, k: &[u8; 32]) -> Result<()> {
+            self.writes += 1;
+            self.sto.delete(k)
+        }
+        fn batch(&mut self, b: db::Batch) -> Result<()> {
+            self.writes += 1;
+            self.sto.batch(b)
+        }
+        fn keys(&mut self) -> Result<Vec<[u8; 32]>> {
+            self.sto.keys()
+        }
+    }
+
+    #[test]
+    fn test_transaction_commit() {
+        let mut sto_expected = db::MemoryDb::new();
+        let mut mt_expected = MerkleTree::new(&mut sto_expected, 140).unwrap();
+        for i in 0..8 {
+            mt_expected.add(&val(i)).unwrap();
+        }
+        mt_expected.delete(val(2).hi()).unwrap();
+
+        let mut sto = CountingStorage::default();
+        let mut mt = MerkleTree::new(&mut sto, 140).unwrap();
+        mt.add(&val(0)).unwrap();
+        let root = mt.root;
+
+        let mut tx = mt.transaction();
+        for i in 1..8 {
+            tx.add(&val(i)).unwrap();
+        }
+        tx.delete(val(2).hi()).unwrap();
+        // a failed operation does not break the transaction
+        assert_eq!(Err(Error::KeyAlreadyExists), tx.add(&val(3)));
+        assert_eq!(val(5).bytes, tx.get_value_in_pos(val(5).hi()).unwrap());
+        assert_eq!(mt_expected.root, tx.get_root());
+        tx.commit().unwrap();
+
+        assert_ne!(root, mt.root);
+        assert_eq!(mt_expected.root, mt.root);
+        // one write for the first add and one for the whole transaction
+        assert_eq!(2, mt.sto.writes);
+
+        let mut mt = MerkleTree::new(&mut sto, 140).unwrap();
+        assert_eq!(mt_expected.root, mt.root);
+        assert_eq!(val(7).bytes, mt.get_value_in_pos(val(7).hi()).unwrap());
+        mt.add(&val(2)).unwrap();
+    }
+
+    #[test]
+    fn test_transaction_rollback() {
+        let mut sto = db::MemoryDb::new();
+        let mut mt = MerkleTree::new(&mut sto, 140).unwrap();
+        for i in 0..4 {
+            mt.add(&val(i)).unwrap();
+        }
+        let root = mt.root;
+
+        let mut tx = mt.transaction();
+        tx.add(&val(4)).unwrap();
+        tx.update(&TestValue::new(
+            "1 this is a test updated".as_bytes().to_vec(),
+            15,
+        ))
+        .unwrap();
+        tx.delete(val(0).hi()).unwrap();
+        assert_ne!(root, tx.get_root());
+        tx.rollback();
+        assert_eq!(root, mt.root);
+
+        // a transaction dropped without commit is discarded too
+        {
+            let mut tx = mt.transaction();
+            tx.add(&val(4)).unwrap();
+        }
+        assert_eq!(root, mt.root);
+        assert_eq!(
+            constants::EMPTYNODEVALUE.to_vec(),
+            mt.get_value_in_pos(val(4).hi()).unwrap()
+        );
+        assert_eq!(val(1).bytes, mt.get_value_in_pos(val(1).hi()).unwrap());
+
+        let mut mt = MerkleTree::new(&mut sto, 140).unwrap();
+        assert_eq!(root, mt.root);
+        mt.add(&val(4)).unwrap();
+    }
+}
@@ -1,55 +1,50 @@
 use super::constants;
-use super::node;
-use tiny_keccak::Keccak;
+use super::hasher::Hasher;
+#[cfg(feature = "std")]
+use super::hasher::Keccak256;
 
+#[cfg(feature = "std")]
 pub fn hash_vec(b: Vec<u8>) -> [u8; 32] {
-    let mut sha3 = Keccak::new_keccak256();
-    sha3.update(&b);
-    let mut res: [u8; 32] = [0; 32];
-    sha3.finalize(&mut res);
-    res
+    Keccak256::hash_bytes(&b)
 }
 
+#[cfg(feature = "std")]
 pub fn get_path(num_levels: u32, hi: [u8; 32]) -> Vec<bool> {
-    let mut path = Vec::new();
-    for i in (0..=num_levels as usize - 2).rev() {
-        path.push((hi[hi.len() - i / 8 - 1] & (1 << (i % 8))) > 0);
+    get_path_array(num_levels, hi)[..num_levels as usize - 1].to_vec()
+}
+
+/// Same as `get_path`, without allocating: only the first `num_levels - 1`
+/// bits of the array are the path.
+pub fn get_path_array(num_levels: u32, hi: [u8; 32]) -> [bool; 256] {
+    let mut path = [false; 256];
+    for (p, i) in (0..=num_levels as usize - 2).rev().enumerate() {
+        path[p] = (hi[hi.len() - i / 8 - 1] & (1 << (i % 8))) > 0;
     }
     path
 }
 
-pub fn calc_hash_from_leaf_and_level(
+pub fn calc_hash_from_leaf_and_level<H: Hasher>(
     until_level: u32,
     path: &[bool],
     leaf_hash: [u8; 32],
 ) -> [u8; 32] {
+    if H::COMPACT_LEAVES {
+        return leaf_hash;
+    }
     let mut node_curr_lvl = leaf_hash;
     for i in 0..until_level {
         if path[i as usize] {
-            let node = node::TreeNode {
-                child_l: constants::EMPTYNODEVALUE,
-                child_r: node_curr_lvl,
-            };
-            node_curr_lvl = node.ht();
+            node_curr_lvl = H::hash_node(&constants::EMPTYNODEVALUE, &node_curr_lvl);
         } else {
-            let node = node::TreeNode {
-                child_l: node_curr_lvl,
-                child_r: constants::EMPTYNODEVALUE,
-            };
-            node_curr_lvl = node.ht();
+            node_curr_lvl = H::hash_node(&node_curr_lvl, &constants::EMPTYNODEVALUE);
         }
     }
     node_curr_lvl
 }
 
+#[cfg(feature = "std")]
 pub fn cut_path(path: &[bool], i: usize) -> Vec<bool> {
-    let mut path_res: Vec<bool> = Vec::new();
-    for j in 0..path.len() {
-        if j >= i {
-            path_res.push(path[j]);
-        }
-    }
-    path_res
+    path.iter().skip(i).cloned().collect()
 }
 
 pub fn compare_paths(a: &[bool], b: &[bool]) -> i32 {
@@ -61,15 +56,12 @@ pub fn compare_paths(a: &[bool], b: &[bool]) -> i32 {
     -1
 }
 
+#[cfg(feature = "std")]
 pub fn get_empties_between_i_and_pos(i: u32, pos: u32) -> Vec<[u8; 32]> {
-    let mut sibl: Vec<[u8; 32]> = Vec::new();
-    for _ in (pos..=i).rev() {
-        sibl.push(constants::EMPTYNODEVALUE);
-    }
-    sibl
+    (pos..=i).map(|_| constants::EMPTYNODEVALUE).collect()
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use super::*;
     use rustc_hex::ToHex;
@@ -84,4 +76,24 @@ mod tests {
             h.to_hex()
         );
     }
+
+    #[test]
+    fn test_get_path() {
+        let mut hi = [0; 32];
+        hi[31] = 0b101;
+        hi[30] = 1;
+        let path = get_path(10, hi);
+        // the last bit is the lowest one of hi, used at the root
+        assert_eq!(
+            vec![true, false, false, false, false, false, true, false, true],
+            path
+        );
+        let path_array = get_path_array(10, hi);
+        assert_eq!(path[..], path_array[..9]);
+        assert!(!path_array[9..].contains(&true));
+        assert_eq!(
+            get_path(257, [0xff; 32])[..],
+            get_path_array(257, [0xff; 32])[..]
+        );
+    }
 }